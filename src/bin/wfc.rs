@@ -0,0 +1,188 @@
+use std::sync::Arc;
+use signal_hook::flag;
+use sdl_wfc::wfc::*;
+use sdl_wfc::export::{self, Atlas};
+use sdl_wfc::feasibility;
+use sdl_wfc::search::SeedSearch;
+use sdl_wfc::setup::{apply_setup, parse_args, parse_size, parse_number};
+use sdl_wfc::tilesets::{tileset, Tileset, TILESET_NAMES};
+
+const USAGE: &str = "\
+Headless wave function collapse generator.
+
+usage:
+  wfc generate [options]   generate a map and write it out
+  wfc validate [options]   lint the tileset and check whether the map can be filled
+  wfc tilesets             list available tilesets
+
+options:
+  --tileset NAME      tileset to use (default: pipes)
+  --size XxY[xZ]      map size (default: 10x10x1)
+  --seed N            first seed to try (default: 0)
+  --setup FILE        setup file with boundaries, tiles and constraints
+  --attempts N        how many seeds to try (default: 100)
+  --threads N         threads trying seeds (default: all cores)
+  --format FORMAT     output format: text, save or png (default: text)
+  --output FILE       write to FILE instead of stdout, png needs it,
+                      layers of 3d maps are written to FILE_0.png, FILE_1.png, ...
+  --exhaustive N      validate: also try every combination on maps up to N squares
+";
+
+/// Size of tiles in png output of tilesets without an image.
+const PLAIN_TILE_SIZE: u32 = 8;
+
+struct Options {
+    tileset: Tileset,
+    size: [usize; 3],
+    seed: u64,
+    setup: Option<String>,
+    attempts: u64,
+    threads: Option<usize>,
+    format: String,
+    output: Option<String>,
+    exhaustive: usize,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut values = parse_args(args)?;
+    let name = values.remove("tileset").unwrap_or("pipes");
+    let tileset = tileset(name).ok_or_else(|| format!("error: unknown tileset '{}', see `wfc tilesets`", name))?;
+    let options = Options {
+        tileset,
+        size: parse_size(values.remove("size").unwrap_or("10x10x1"))?,
+        seed: parse_number("seed", values.remove("seed").unwrap_or("0"))?,
+        setup: values.remove("setup").map(|x| x.to_string()),
+        attempts: parse_number("attempts", values.remove("attempts").unwrap_or("100"))?,
+        threads: values.remove("threads").map(|x| parse_number("threads", x)).transpose()?,
+        format: values.remove("format").unwrap_or("text").to_string(),
+        output: values.remove("output").map(|x| x.to_string()),
+        exhaustive: parse_number("exhaustive", values.remove("exhaustive").unwrap_or("0"))?,
+    };
+    if let Some(name) = values.keys().next() {
+        return Err(format!("error: unknown option --{}", name));
+    }
+    Ok(options)
+}
+
+/// Fresh solver with the setup file applied.
+fn prepare(options: &Options) -> Result<WFC, String> {
+    let [x, y, z] = options.size;
+    let mut wfc = WFC::init(Worldmap::new3d(x, y, z), options.tileset.tiles.clone(), options.seed);
+    wfc.tags = options.tileset.tags.clone();
+    if let Some(path) = &options.setup {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("error: can't read {}: {}", path, e))?;
+        apply_setup(&mut wfc, &text)?;
+    }
+    Ok(wfc)
+}
+
+fn write_output(options: &Options, text: &str) -> Result<(), String> {
+    match &options.output {
+        Some(path) => std::fs::write(path, text).map_err(|e| format!("error: can't write {}: {}", path, e)),
+        None => {
+            print!("{}", text);
+            Ok(())
+        },
+    }
+}
+
+/// Tilesets without an image (or whose image can't be read) are drawn as squares coloured by tile index.
+fn write_png(options: &Options, worldmap: &Worldmap) -> Result<(), String> {
+    let tileset = &options.tileset;
+    let atlas = match tileset.image.as_str() {
+        "" => None,
+        path => match Atlas::load(path, tileset.tilemap_size) {
+            Ok(atlas) => Some(atlas),
+            Err(e) => {
+                eprintln!("{}, drawing plain tiles", e);
+                None
+            },
+        },
+    };
+    for path in export::save_png(worldmap, atlas.as_ref(), PLAIN_TILE_SIZE, options.output.as_ref().unwrap())? {
+        eprintln!("wrote {}", path);
+    }
+    Ok(())
+}
+
+fn generate(options: &Options) -> Result<(), String> {
+    match options.format.as_str() {
+        "text" | "save" => (),
+        "png" if options.output.is_none() => return Err("error: png format needs --output".to_string()),
+        "png" => (),
+        x => return Err(format!("error: unknown format '{}'", x)),
+    }
+    let wfc = prepare(options)?;
+    let mut search = SeedSearch::new(options.seed..options.seed.saturating_add(options.attempts));
+    if let Some(threads) = options.threads {
+        search.threads = threads;
+    }
+    flag::register(signal_hook::consts::SIGINT, Arc::clone(&search.cancel)).map_err(|e| e.to_string())?;
+
+    let (found, stats) = search.run(&wfc);
+    eprintln!("tried {} seeds, {} succeeded, {} backtracks", stats.tried, stats.successes, stats.backtracks);
+    for (error, count) in &stats.errors {
+        eprintln!("{:>6}x {}", count, error);
+    }
+    if stats.is_cancelled {
+        return Err("error: cancelled".to_string());
+    }
+    let (seed, wfc) = found.ok_or_else(|| format!("error: no seed out of {} succeeded", stats.tried))?;
+    eprintln!("seed: {}", seed);
+    match options.format.as_str() {
+        "save" => write_output(options, &wfc.save()),
+        "png" => write_png(options, &wfc.worldmap),
+        _ => write_output(options, &export::to_text(&wfc.worldmap)),
+    }
+}
+
+fn validate(options: &Options) -> Result<(), String> {
+    let issues = options.tileset.validate();
+    for issue in &issues {
+        println!("warning: {}", issue);
+    }
+
+    let wfc = prepare(options)?;
+    let feasibility = feasibility::check(&wfc);
+    if let Some(contradiction) = &feasibility.contradiction {
+        return Err(format!("error: map can't be filled: {}", contradiction));
+    }
+    for index in &feasibility.impossible {
+        println!("warning: tile {} can't appear on this map", index);
+    }
+    if options.exhaustive > 0 {
+        match feasibility::exhaustive(&wfc, options.exhaustive)? {
+            Some(_) => println!("map can be filled"),
+            None => return Err("error: map can't be filled, no combination of tiles fits".to_string()),
+        }
+    }
+    println!("{} tileset issue(s), {} tile(s) can't appear on the map", issues.len(), feasibility.impossible.len());
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    match args.first().map(|x| x.as_str()) {
+        Some("generate") => generate(&parse_options(&args[1..])?),
+        Some("validate") => validate(&parse_options(&args[1..])?),
+        Some("tilesets") => {
+            for name in TILESET_NAMES {
+                println!("{}", name);
+            }
+            Ok(())
+        },
+        Some("help") | Some("--help") | Some("-h") => {
+            print!("{}", USAGE);
+            Ok(())
+        },
+        Some(x) => Err(format!("error: unknown command '{}'\n\n{}", x, USAGE)),
+        None => Err(USAGE.to_string()),
+    }
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
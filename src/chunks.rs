@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use crate::wfc::{WfcTile, Worldmap, WFC, GENERATED_CONNECTIONS};
+use crate::rng;
+
+/// Horizontal chunk coordinate, chunks span the whole height of the world.
+pub type ChunkCoord = [i64; 2];
+
+/// Unbounded world generated one chunk at a time, on demand.
+///
+/// Faces of a new chunk touching already generated chunks must agree with them, faces towards
+/// chunks which don't exist yet are free, except that big tiles can't cross them. Every chunk is
+/// solved with a seed derived from the world seed and its coordinate, so a chunk comes out the same
+/// for the same world seed and the same set of already generated neighbours.
+///
+/// When a chunk can't be solved against its neighbours, a margin of `margin` squares of the
+/// neighbouring chunks around it is generated again together with it, widening up to `max_margin`.
+pub struct ChunkedWorld {
+    pub tiles: Vec<WfcTile>,
+    pub chunk_size: [usize; 3],
+    pub seed: u64,
+    /// seeds tried for every margin before widening it
+    pub attempts: u64,
+    pub max_margin: usize,
+    pub chunks: HashMap<ChunkCoord, Worldmap>,
+}
+
+/// Mixes world seed and chunk coordinate into a seed for the chunk solver.
+pub fn chunk_seed(seed: u64, coord: ChunkCoord) -> u64 {
+    rng::hash(seed, &[coord[0] as u64, coord[1] as u64])
+}
+
+impl ChunkedWorld {
+    pub fn new(tiles: Vec<WfcTile>, chunk_size: [usize; 3], seed: u64) -> ChunkedWorld {
+        ChunkedWorld {
+            tiles,
+            chunk_size,
+            seed,
+            attempts: 10,
+            max_margin: 2,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Chunk containing global square `[x, y, z]` and position of the square inside it.
+    pub fn locate(&self, square: [i64; 3]) -> (ChunkCoord, [usize; 3]) {
+        let [x_size, y_size, _] = self.chunk_size;
+        let coord = [square[0].div_euclid(x_size as i64), square[1].div_euclid(y_size as i64)];
+        let local = [square[0].rem_euclid(x_size as i64) as usize, square[1].rem_euclid(y_size as i64) as usize, square[2] as usize];
+        (coord, local)
+    }
+
+    /// Solved tile of global square `[x, y, z]`, `None` if its chunk isn't generated.
+    pub fn tile_at(&self, square: [i64; 3]) -> Option<&WfcTile> {
+        if square[2] < 0 || square[2] as usize >= self.chunk_size[2] {
+            return None;
+        }
+        let (coord, local) = self.locate(square);
+        self.chunks.get(&coord)?[local].first()
+    }
+
+    pub fn is_generated(&self, coord: ChunkCoord) -> bool {
+        self.chunks.contains_key(&coord)
+    }
+
+    /// Forgets a chunk, it is generated again by the next `get`.
+    pub fn unload(&mut self, coord: ChunkCoord) -> Option<Worldmap> {
+        self.chunks.remove(&coord)
+    }
+
+    /// Returns the chunk at `coord`, generating it first if needed.
+    pub fn get(&mut self, coord: ChunkCoord) -> Result<&Worldmap, String> {
+        if !self.is_generated(coord) {
+            self.generate(coord)?;
+        }
+        Ok(&self.chunks[&coord])
+    }
+
+    /// Generates chunk at `coord` (again, if it already exists) against its generated neighbours.
+    /// Returns the margin of neighbouring squares which had to be generated again with it.
+    pub fn generate(&mut self, coord: ChunkCoord) -> Result<usize, String> {
+        self.chunks.remove(&coord);
+        let mut last_error = String::new();
+        for margin in 0..=self.max_margin {
+            for attempt in 0..self.attempts {
+                let seed = chunk_seed(self.seed, coord).wrapping_add(attempt);
+                match self.solve(coord, margin, seed) {
+                    Ok(()) => return Ok(margin),
+                    Err(e) => last_error = e,
+                }
+            }
+        }
+        Err(format!("error: can't generate chunk {:?} with margin up to {}, last error: {}", coord, self.max_margin, last_error))
+    }
+
+    /// Solves chunk at `coord` together with `margin` squares of generated neighbours around it.
+    /// Neighbour squares just outside the margin are fixed, chunks which don't exist are masked out.
+    fn solve(&mut self, coord: ChunkCoord, margin: usize, seed: u64) -> Result<(), String> {
+        let [x_size, y_size, z_size] = self.chunk_size;
+        let border = margin + 1;
+        let (width, height) = (x_size + 2*border, y_size + 2*border);
+        let origin = [coord[0] * x_size as i64 - border as i64, coord[1] * y_size as i64 - border as i64];
+        let global = |x: usize, y: usize, z: usize| [origin[0] + x as i64, origin[1] + y as i64, z as i64];
+
+        let mut worldmap = Worldmap::with_mask(width, height, z_size, |[x, y, z]| {
+            let (chunk, _) = self.locate(global(x, y, z));
+            chunk == coord || self.is_generated(chunk)
+        });
+        // big tiles can't continue into chunks which don't exist yet
+        let mut sockets: Vec<usize> = self.tiles.iter()
+            .flat_map(|tile| tile.connection_types.iter().cloned())
+            .filter(|&socket| socket < GENERATED_CONNECTIONS)
+            .collect();
+        sockets.sort();
+        sockets.dedup();
+        worldmap.set_mask_sockets(Some(sockets));
+        let mut wfc = WFC::init(worldmap, self.tiles.clone(), seed);
+
+        let mut fixed = Vec::new();
+        for z in 0..z_size {
+        for y in 0..height {
+        for x in 0..width {
+            let is_edge = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+            if !is_edge || !wfc.worldmap.is_active([x, y, z]) {
+                continue;
+            }
+            if let Some(&tile) = self.tile_at(global(x, y, z)) {
+                fixed.push(([x, y, z], tile));
+            }
+        }
+        }
+        }
+        for (square, tile) in fixed {
+            wfc.add_tile(square, tile)?;
+        }
+        wfc.run()?;
+
+        // write back the chunk and the regenerated margin of its neighbours
+        self.chunks.insert(coord, Worldmap::new3d(x_size, y_size, z_size));
+        for z in 0..z_size {
+        for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            if !wfc.worldmap.is_active([x, y, z]) {
+                continue;
+            }
+            let (chunk, local) = self.locate(global(x, y, z));
+            self.chunks.get_mut(&chunk).unwrap()[local] = wfc.worldmap[[x, y, z]].clone();
+        }
+        }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilesets::pipes;
+
+    fn generate_all(world: &mut ChunkedWorld, coords: &[ChunkCoord]) {
+        for &coord in coords {
+            world.generate(coord).unwrap();
+        }
+    }
+
+    fn grid(from: i64, to: i64) -> Vec<ChunkCoord> {
+        (from..=to).flat_map(|y| (from..=to).map(move |x| [x, y])).collect()
+    }
+
+    /// Every pair of neighbouring squares in `from..=to` agrees on its sockets.
+    fn assert_borders_agree(world: &ChunkedWorld, from: [i64; 2], to: [i64; 2]) {
+        for y in from[1]..=to[1] {
+        for x in from[0]..=to[0] {
+            let tile = world.tile_at([x, y, 0]).unwrap();
+            if x < to[0] {
+                let east = world.tile_at([x + 1, y, 0]).unwrap();
+                assert_eq!(tile.connection_types[1], east.connection_types[3], "{:?}", [x, y]);
+            }
+            if y < to[1] {
+                let south = world.tile_at([x, y + 1, 0]).unwrap();
+                assert_eq!(tile.connection_types[2], south.connection_types[0], "{:?}", [x, y]);
+            }
+        }
+        }
+    }
+
+    #[test]
+    fn borders_agree() {
+        let (_, tiles, _) = pipes();
+        let mut world = ChunkedWorld::new(tiles, [4, 3, 1], 5);
+        generate_all(&mut world, &grid(-1, 1));
+        assert_borders_agree(&world, [-4, -3], [7, 5]);
+    }
+
+    #[test]
+    fn same_order_gives_same_world() {
+        let (_, tiles, _) = pipes();
+        let coords = [[0, 0], [1, 0], [-1, 0], [0, -1], [1, 1]];
+        let mut world = ChunkedWorld::new(tiles.clone(), [4, 4, 1], 5);
+        let mut again = ChunkedWorld::new(tiles, [4, 4, 1], 5);
+        generate_all(&mut world, &coords);
+        generate_all(&mut again, &coords);
+        for coord in coords {
+            assert_eq!(world.chunks[&coord].values, again.chunks[&coord].values, "{:?}", coord);
+        }
+    }
+
+    #[test]
+    fn chunk_seeds_differ() {
+        let mut seeds: Vec<u64> = grid(-3, 3).into_iter().map(|coord| chunk_seed(5, coord)).collect();
+        seeds.sort();
+        seeds.dedup();
+        assert_eq!(seeds.len(), 49);
+        assert_ne!(chunk_seed(5, [0, 0]), chunk_seed(6, [0, 0]));
+    }
+
+    #[test]
+    fn widens_margin_on_contradiction() {
+        let (_, tiles, _) = pipes();
+        let empty = tiles[1];
+        let red_end = *tiles.iter().find(|x| x.connection_types[1] == 2).unwrap();
+        let mut world = ChunkedWorld::new(tiles, [1, 1, 1], 5);
+        // no tile has a single red socket, so the chunk between these can't be solved on its own
+        for (coord, tile) in [([-1, 0], red_end), ([1, 0], empty), ([0, -1], empty), ([0, 1], empty)] {
+            let mut chunk = Worldmap::new3d(1, 1, 1);
+            chunk[[0, 0, 0]] = vec![tile];
+            world.chunks.insert(coord, chunk);
+        }
+        world.max_margin = 0;
+        assert!(world.generate([0, 0]).is_err());
+        assert!(!world.is_generated([0, 0]));
+        assert_eq!(world.tile_at([-1, 0, 0]), Some(&red_end));
+
+        world.max_margin = 2;
+        assert_eq!(world.generate([0, 0]), Ok(1));
+        assert_ne!(world.tile_at([-1, 0, 0]), Some(&red_end));
+        assert_borders_agree(&world, [-1, 0], [1, 0]);
+        assert_borders_agree(&world, [0, -1], [0, 1]);
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+use crate::wfc::{WfcTile, Worldmap, Position, Direction, WFC};
+use crate::paths;
+
+/// Named tags attached to tiles (by `WfcTile::index`) and sockets, so constraints can
+/// say "road" instead of listing indices.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tags {
+    pub tiles: HashMap<u32, Vec<String>>,
+    pub sockets: HashMap<usize, Vec<String>>,
+}
+
+impl Tags {
+    pub fn tag_tile(&mut self, index: u32, tag: &str) -> &mut Self {
+        let tags = self.tiles.entry(index).or_default();
+        if !tags.iter().any(|x| x == tag) {
+            tags.push(tag.to_string());
+        }
+        self
+    }
+
+    pub fn tag_socket(&mut self, socket: usize, tag: &str) -> &mut Self {
+        let tags = self.sockets.entry(socket).or_default();
+        if !tags.iter().any(|x| x == tag) {
+            tags.push(tag.to_string());
+        }
+        self
+    }
+
+    pub fn tile_has(&self, tile: &WfcTile, tag: &str) -> bool {
+        self.tiles.get(&tile.index).is_some_and(|tags| tags.iter().any(|x| x == tag))
+    }
+
+    pub fn socket_has(&self, socket: usize, tag: &str) -> bool {
+        self.sockets.get(&socket).is_some_and(|tags| tags.iter().any(|x| x == tag))
+    }
+
+    /// Tile indices tagged with `tag`, sorted.
+    pub fn tiles_tagged(&self, tag: &str) -> Vec<u32> {
+        let mut rv: Vec<u32> = self.tiles.keys().filter(|&&index| self.tiles[&index].iter().any(|x| x == tag)).cloned().collect();
+        rv.sort();
+        rv
+    }
+
+    /// Sockets tagged with `tag`, sorted.
+    pub fn sockets_tagged(&self, tag: &str) -> Vec<usize> {
+        let mut rv: Vec<usize> = self.sockets.keys().filter(|&&socket| self.sockets[&socket].iter().any(|x| x == tag)).cloned().collect();
+        rv.sort();
+        rv
+    }
+}
+
+/// Set of worldmap cells a constraint applies to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    /// Box between two corners, both inclusive.
+    Box(Position, Position),
+    /// One flag per worldmap cell, in worldmap order (x, then y, then z).
+    Mask(Vec<bool>),
+}
+
+impl Region {
+    /// Evaluates `predicate` for every cell of `worldmap` and stores the result as a mask.
+    pub fn from_fn<F: Fn(Position) -> bool>(worldmap: &Worldmap, predicate: F) -> Region {
+        let [x_size, y_size, z_size] = worldmap.size;
+        let mut mask = vec![false; worldmap.len];
+        for z in 0..z_size {
+        for y in 0..y_size {
+        for x in 0..x_size {
+            mask[worldmap.index_of([x, y, z])] = predicate([x, y, z]);
+        }
+        }
+        }
+        Region::Mask(mask)
+    }
+
+    /// Whole horizontal layer `z` of the worldmap.
+    pub fn layer(worldmap: &Worldmap, z: usize) -> Region {
+        let [x_size, y_size, _] = worldmap.size;
+        Region::Box([0, 0, z], [x_size - 1, y_size - 1, z])
+    }
+
+    pub fn contains(&self, worldmap: &Worldmap, square: Position) -> bool {
+        match self {
+            Region::Box(from, to) => (0..3).all(|i| {
+                let (lo, hi) = (from[i].min(to[i]), from[i].max(to[i]));
+                lo <= square[i] && square[i] <= hi
+            }),
+            Region::Mask(mask) => *mask.get(worldmap.index_of(square)).unwrap_or(&false),
+        }
+    }
+
+    /// Checks that the region fits `worldmap`: box corners lie on it and a mask has a flag for every cell.
+    pub fn check(&self, worldmap: &Worldmap) -> Result<(), String> {
+        match self {
+            Region::Box(from, to) if !worldmap.contains(*from) || !worldmap.contains(*to) =>
+                Err(format!("error: region {:?} is outside of the map", self)),
+            Region::Box(..) => Ok(()),
+            Region::Mask(mask) if mask.len() != worldmap.len =>
+                Err(format!("error: region mask has {} cells, expected {}", mask.len(), worldmap.len)),
+            Region::Mask(_) => Ok(()),
+        }
+    }
+
+    /// Region extended by `n` squares in every direction, clamped to the worldmap.
+    pub fn grow(&self, worldmap: &Worldmap, n: usize) -> Region {
+        match self {
+            Region::Box(from, to) => {
+                let lo = [0, 1, 2].map(|i| from[i].min(to[i]).saturating_sub(n));
+                let hi = [0, 1, 2].map(|i| (from[i].max(to[i]) + n).min(worldmap.size[i] - 1));
+                Region::Box(lo, hi)
+            },
+            Region::Mask(_) => {
+                let mut squares = self.squares(worldmap);
+                let mut mask = vec![false; worldmap.len];
+                for &square in &squares {
+                    mask[worldmap.index_of(square)] = true;
+                }
+                for _ in 0..n {
+                    let mut next_squares = Vec::new();
+                    for square in squares {
+                        for dir in 0..6 {
+                            if let Some(next) = worldmap.move_(square, &Direction::from(dir)) {
+                                if !mask[worldmap.index_of(next)] {
+                                    mask[worldmap.index_of(next)] = true;
+                                    next_squares.push(next);
+                                }
+                            }
+                        }
+                    }
+                    squares = next_squares;
+                }
+                Region::Mask(mask)
+            },
+        }
+    }
+
+    /// Active cells of `worldmap` inside the region, in worldmap order.
+    pub fn squares(&self, worldmap: &Worldmap) -> Vec<Position> {
+        let [x_size, y_size, z_size] = worldmap.size;
+        let mut squares = Vec::new();
+        for z in 0..z_size {
+        for y in 0..y_size {
+        for x in 0..x_size {
+            if worldmap.is_active([x, y, z]) && self.contains(worldmap, [x, y, z]) {
+                squares.push([x, y, z]);
+            }
+        }
+        }
+        }
+        squares
+    }
+}
+
+/// Selects tiles, all rotations of a tile are selected together.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TileFilter {
+    /// Tiles with one of the given `WfcTile::index`.
+    Indices(Vec<u32>),
+    /// Tiles having any of the given tags.
+    Tags(Vec<String>),
+    /// Tiles having a socket with any of the given tags on any horizontal side.
+    /// Vertical sockets are shifted per rotation (see `WfcTile::rotate`), so they are not tagged.
+    SocketTags(Vec<String>),
+    Not(Box<TileFilter>),
+}
+
+impl TileFilter {
+    pub fn tag(tag: &str) -> TileFilter {
+        TileFilter::Tags(vec![tag.to_string()])
+    }
+
+    pub fn socket_tag(tag: &str) -> TileFilter {
+        TileFilter::SocketTags(vec![tag.to_string()])
+    }
+
+    pub fn negate(filter: TileFilter) -> TileFilter {
+        TileFilter::Not(Box::new(filter))
+    }
+
+    pub fn matches(&self, tags: &Tags, tile: &WfcTile) -> bool {
+        match self {
+            TileFilter::Indices(indices) => indices.contains(&tile.index),
+            TileFilter::Tags(names) => names.iter().any(|tag| tags.tile_has(tile, tag)),
+            TileFilter::SocketTags(names) => tile.connection_types[0..4].iter()
+                .any(|&socket| names.iter().any(|tag| tags.socket_has(socket, tag))),
+            TileFilter::Not(filter) => !filter.matches(tags, tile),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constraint {
+    /// Cells inside the region can only hold tiles matching the filter.
+    Restrict(Region, TileFilter),
+    /// Number of cells holding a tile matching the filter must be within `min..=max`.
+    Count { filter: TileFilter, min: usize, max: usize },
+    /// All squares holding tiles with one of the sockets (e.g. roads) must form a single
+    /// connected network, which also passes through every endpoint.
+    Connected { sockets: Vec<usize>, endpoints: Vec<Position> },
+    /// Network of the sockets must link the first endpoint with every other one. The shortest
+    /// route to each of them, counted in steps between squares, must be within `min..=max`.
+    Path { sockets: Vec<usize>, endpoints: Vec<Position>, min: usize, max: usize },
+    /// Keeps an existing route of neighbouring squares: each square must connect to the previous
+    /// and the next one with `roads` sockets, and face other squares of the route with `walls`
+    /// sockets so the route can't take shortcuts. Empty `walls` leaves side faces unconstrained.
+    PreservePath { route: Vec<Position>, roads: Vec<usize>, walls: Vec<usize> },
+}
+
+impl Constraint {
+    pub fn exactly(filter: TileFilter, count: usize) -> Constraint {
+        Constraint::Count { filter, min: count, max: count }
+    }
+
+    pub fn at_most(filter: TileFilter, count: usize) -> Constraint {
+        Constraint::Count { filter, min: 0, max: count }
+    }
+
+    pub fn at_least(filter: TileFilter, count: usize) -> Constraint {
+        Constraint::Count { filter, min: count, max: usize::MAX }
+    }
+
+    /// Network of sockets tagged with `tag` must be connected and pass through `endpoints`.
+    pub fn connected(tags: &Tags, tag: &str, endpoints: Vec<Position>) -> Constraint {
+        Constraint::Connected { sockets: tags.sockets_tagged(tag), endpoints }
+    }
+
+    /// Path of sockets tagged with `tag` between `endpoints`, of any length.
+    pub fn path(tags: &Tags, tag: &str, endpoints: Vec<Position>) -> Constraint {
+        Constraint::Path { sockets: tags.sockets_tagged(tag), endpoints, min: 0, max: usize::MAX }
+    }
+
+    /// At least `fraction` (0.0 - 1.0) of active worldmap cells.
+    pub fn at_least_fraction(worldmap: &Worldmap, filter: TileFilter, fraction: f64) -> Constraint {
+        let cells = worldmap.active.iter().filter(|&&x| x).count();
+        Constraint::at_least(filter, (cells as f64 * fraction).ceil() as usize)
+    }
+
+    /// Checks that every square the constraint refers to lies on `worldmap`.
+    pub fn check(&self, worldmap: &Worldmap) -> Result<(), String> {
+        let squares = match self {
+            Constraint::Restrict(region, _) => return region.check(worldmap),
+            Constraint::Count { .. } => return Ok(()),
+            Constraint::Connected { endpoints, .. } | Constraint::Path { endpoints, .. } => endpoints,
+            Constraint::PreservePath { route, .. } => route,
+        };
+        match squares.iter().find(|&&x| !worldmap.contains(x)) {
+            Some(square) => Err(format!("error: square {:?} is outside of the map", square)),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes tiles violating the constraint from worldmap and propagates the change.
+    /// Returns true if worldmap was changed.
+    pub fn apply(&self, wfc: &mut WFC) -> Result<bool, String> {
+        match self {
+            Constraint::Restrict(region, filter) => {
+                let mut is_changed = false;
+                for square in region.squares(&wfc.worldmap) {
+                    let tags = &wfc.tags;
+                    let stack = &wfc.worldmap[square];
+                    if stack.iter().all(|tile| filter.matches(tags, tile)) {
+                        continue;
+                    }
+                    let stack: Vec<WfcTile> = stack.iter().filter(|tile| filter.matches(tags, tile)).cloned().collect();
+                    if stack.is_empty() {
+                        return Err(format!("error: region constraint {:?} is unsatisfiable, square {:?} has no allowed tiles", self, square));
+                    }
+                    wfc.set_stack(square, stack);
+                    is_changed = true;
+                    if let Err(e) = wfc.propagate(square) {
+                        return Err(format!("error: region constraint {:?} is unsatisfiable, restricting square {:?} led to {}", self, square, e));
+                    }
+                }
+                Ok(is_changed)
+            },
+            Constraint::Count { filter, min, max } => {
+                let mut is_changed = false;
+                loop {
+                    // `certain` squares can only hold matching tiles,
+                    // `open` ones could hold both matching and other tiles.
+                    let mut certain = 0;
+                    let mut open = Vec::new();
+                    for &square in &wfc.squares_list {
+                        if !wfc.worldmap.is_active(square) {
+                            continue;
+                        }
+                        let stack = &wfc.worldmap[square];
+                        let matching = stack.iter().filter(|tile| filter.matches(&wfc.tags, tile)).count();
+                        if matching == stack.len() {
+                            certain += 1;
+                        } else if matching > 0 {
+                            open.push(square);
+                        }
+                    }
+
+                    if certain > *max {
+                        return Err(format!("error: count constraint {:?} is violated, {} squares already hold matching tiles", self, certain));
+                    }
+                    if certain + open.len() < *min {
+                        return Err(format!("error: count constraint {:?} is violated, only {} squares can hold matching tiles", self, certain + open.len()));
+                    }
+                    // maximum is reached: nothing else can match
+                    // minimum can be reached only by using every open square: all of them must match
+                    let keep_matching = if certain == *max {
+                        false
+                    } else if certain + open.len() == *min {
+                        true
+                    } else {
+                        return Ok(is_changed);
+                    };
+                    if open.is_empty() {
+                        return Ok(is_changed);
+                    }
+
+                    for square in open {
+                        let tags = &wfc.tags;
+                        let stack: Vec<WfcTile> = wfc.worldmap[square].iter()
+                            .filter(|tile| filter.matches(tags, tile) == keep_matching)
+                            .cloned()
+                            .collect();
+                        if stack.len() == wfc.worldmap[square].len() {
+                            continue;
+                        }
+                        if stack.is_empty() {
+                            return Err(format!("error: count constraint {:?} is violated, square {:?} has no allowed tiles", self, square));
+                        }
+                        wfc.set_stack(square, stack);
+                        is_changed = true;
+                        wfc.propagate(square)?;
+                    }
+                }
+            },
+            Constraint::Connected { sockets, endpoints } => {
+                let mut is_changed = false;
+                for &square in endpoints {
+                    let faces = paths::open_faces(&wfc.worldmap, square);
+                    is_changed |= wfc.retain(square, |tile| paths::tile_carries(tile, &faces, sockets))
+                        .map_err(|e| format!("error: connectivity constraint {:?} can't reach endpoint {:?}: {}", self, square, e))?;
+                }
+
+                // squares which are surely part of the network
+                let worldmap = &wfc.worldmap;
+                let mut required = endpoints.clone();
+                for &square in &wfc.squares_list {
+                    let stack = &worldmap[square];
+                    let faces = paths::open_faces(worldmap, square);
+                    if !stack.is_empty() && stack.iter().all(|tile| paths::tile_carries(tile, &faces, sockets)) {
+                        required.push(square);
+                    }
+                }
+                let start = match required.first() {
+                    Some(x) => *x,
+                    None => return Ok(is_changed),
+                };
+
+                let reached = paths::reachable(worldmap, sockets, start);
+                if let Some(square) = required.iter().find(|&&x| !reached[worldmap.index_of(x)]) {
+                    return Err(format!("error: connectivity constraint {:?} is violated, square {:?} is cut off from {:?}", self, square, start));
+                }
+
+                // squares which can't be reached must stay out of the network
+                let cut_off: Vec<Position> = wfc.squares_list.iter()
+                    .filter(|&&square| !reached[worldmap.index_of(square)])
+                    .cloned()
+                    .collect();
+                for square in cut_off {
+                    let faces = paths::open_faces(&wfc.worldmap, square);
+                    is_changed |= wfc.retain(square, |tile| !paths::tile_carries(tile, &faces, sockets))
+                        .map_err(|e| format!("error: connectivity constraint {:?} is violated: {}", self, e))?;
+                }
+                Ok(is_changed)
+            },
+            Constraint::Path { sockets, endpoints, min, max } => {
+                let mut is_changed = false;
+                for &square in endpoints {
+                    let faces = paths::open_faces(&wfc.worldmap, square);
+                    is_changed |= wfc.retain(square, |tile| paths::tile_carries(tile, &faces, sockets))
+                        .map_err(|e| format!("error: path constraint {:?} can't reach endpoint {:?}: {}", self, square, e))?;
+                }
+                let start = match endpoints.first() {
+                    Some(x) => *x,
+                    None => return Ok(is_changed),
+                };
+
+                // tiles of both ends must lead towards each other, tiles sending the route
+                // only where the other end can't be reached in time can't lie on any path
+                for &square in endpoints[1..].iter().filter(|&&x| x != start) {
+                    for (from, to) in [(start, square), (square, start)] {
+                        let worldmap = &wfc.worldmap;
+                        let distance = paths::distances(worldmap, sockets, from, false);
+                        let keep: Vec<WfcTile> = worldmap[to].iter()
+                            .filter(|tile| paths::leads_within(worldmap, to, tile, sockets, &distance, *max))
+                            .cloned()
+                            .collect();
+                        is_changed |= wfc.retain(to, |tile| keep.contains(tile))
+                            .map_err(|e| format!("error: path constraint {:?} can't reach endpoint {:?}: {}", self, to, e))?;
+                    }
+                }
+
+                // shortest route possible with the remaining tiles can only get longer,
+                // route already built can only get shorter.
+                let worldmap = &wfc.worldmap;
+                let possible = paths::distances(worldmap, sockets, start, false);
+                let certain = paths::distances(worldmap, sockets, start, true);
+                for &square in &endpoints[1..] {
+                    let idx = worldmap.index_of(square);
+                    match possible[idx] {
+                        None => return Err(format!("error: path constraint {:?} is violated, {:?} can't be reached", self, square)),
+                        Some(x) if x > *max => return Err(format!("error: path constraint {:?} is violated, {:?} is at least {} steps away", self, square, x)),
+                        _ => (),
+                    }
+                    if let Some(x) = certain[idx] {
+                        if x < *min {
+                            return Err(format!("error: path constraint {:?} is violated, {:?} is only {} steps away", self, square, x));
+                        }
+                    }
+                }
+                Ok(is_changed)
+            },
+            Constraint::PreservePath { route, roads, walls } => {
+                let mut is_changed = false;
+                for (i, &square) in route.iter().enumerate() {
+                    let mut road_dirs = Vec::new();
+                    for &other in route[i.saturating_sub(1)..(i + 2).min(route.len())].iter().filter(|&&x| x != square) {
+                        match paths::direction_between(&wfc.worldmap, square, other) {
+                            Some(dir) => road_dirs.push(dir.into()),
+                            None => return Err(format!("error: preserved path is broken, {:?} and {:?} are not neighbours", square, other)),
+                        }
+                    }
+                    let mut wall_dirs = Vec::new();
+                    for dir in 0..6 {
+                        if let Some(next) = wfc.worldmap.move_(square, &Direction::from(dir)) {
+                            if !road_dirs.contains(&dir) && route.contains(&next) {
+                                wall_dirs.push(dir);
+                            }
+                        }
+                    }
+
+                    is_changed |= wfc.retain(square, |tile| {
+                        road_dirs.iter().all(|&dir| roads.contains(&tile.connection_types[dir]))
+                            && (walls.is_empty() || wall_dirs.iter().all(|&dir| walls.contains(&tile.connection_types[dir])))
+                    }).map_err(|e| format!("error: preserved path can't pass through square {:?}: {}", square, e))?;
+                }
+                Ok(is_changed)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilesets::{pipes, flat_city, flat_city_tags};
+
+    #[test]
+    fn restricts_region() {
+        let (_, tiles, _) = pipes();
+        let empty = tiles[1];
+        let region = Region::Box([1, 1, 0], [3, 2, 0]);
+        let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), tiles.clone(), 0);
+        wfc.restrict(region.clone(), TileFilter::Indices(vec![empty.index])).unwrap();
+        wfc.run().unwrap();
+        for square in region.squares(&wfc.worldmap) {
+            assert_eq!(wfc.worldmap[square], [empty], "{:?}", square);
+        }
+
+        // a square can't be both empty and a line
+        let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), tiles.clone(), 0);
+        wfc.restrict(region, TileFilter::Indices(vec![empty.index])).unwrap();
+        let error = wfc.restrict(Region::Box([2, 2, 0], [2, 2, 0]), TileFilter::Indices(vec![tiles[2].index])).unwrap_err();
+        assert!(error.contains("unsatisfiable"), "{}", error);
+        assert_eq!(wfc.constraints.len(), 1);
+
+        // failed restriction propagated into other squares before it failed, that is taken back too
+        let before = wfc.worldmap.values.clone();
+        assert!(wfc.restrict(Region::Box([0, 0, 0], [5, 5, 0]), TileFilter::Indices(vec![tiles[2].index])).is_err());
+        assert_eq!(wfc.worldmap.values, before);
+        wfc.wfc_step().unwrap();
+        let before = wfc.worldmap.values.clone();
+        assert!(wfc.restrict(Region::Box([0, 0, 0], [5, 5, 0]), TileFilter::Indices(vec![tiles[2].index])).is_err());
+        assert_eq!(wfc.worldmap.values, before);
+    }
+
+    #[test]
+    fn filters_by_tags() {
+        let (_, tiles, _) = flat_city();
+        let tags = flat_city_tags();
+        assert_eq!(tags.tiles_tagged("road"), [2, 3, 5, 13]);
+        assert_eq!(tags.sockets_tagged("road"), [0]);
+
+        let mut wfc = WFC::init(Worldmap::new3d(8, 8, 1), tiles, 3);
+        wfc.tags = tags.clone();
+        let region = Region::Box([0, 0, 0], [7, 3, 0]);
+        wfc.restrict(region.clone(), TileFilter::negate(TileFilter::socket_tag("road"))).unwrap();
+        wfc.run().unwrap();
+
+        let roads = wfc.find(&TileFilter::tag("road"));
+        assert_eq!(wfc.count(&TileFilter::tag("road")), roads.len());
+        for &square in &wfc.squares_list {
+            let tile = &wfc.worldmap[square][0];
+            assert_eq!(roads.contains(&square), tags.tile_has(tile, "road"), "{:?}", square);
+            if region.contains(&wfc.worldmap, square) {
+                assert!(!tile.connection_types[0..4].contains(&0), "{:?}", square);
+            }
+        }
+    }
+
+    #[test]
+    fn honours_count() {
+        let (_, tiles, _) = pipes();
+        let cross = TileFilter::Indices(vec![tiles[3].index]);
+        let mut backtracks = 0;
+        for seed in 0..10 {
+            let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), tiles.clone(), seed);
+            wfc.add_constraint(Constraint::exactly(cross.clone(), 3)).unwrap();
+            wfc.run().unwrap();
+            assert_eq!(wfc.count(&cross), 3, "seed {}", seed);
+            backtracks += wfc.backtracks;
+        }
+        // some seeds run into the count and have to take back earlier steps
+        assert!(backtracks > 0);
+
+        let mut wfc = WFC::init(Worldmap::new3d(2, 2, 1), tiles.clone(), 0);
+        assert!(wfc.add_constraint(Constraint::at_least(cross, 5)).is_err());
+    }
+
+    #[test]
+    fn rejects_squares_outside_of_map() {
+        let (_, tiles, _) = pipes();
+        let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), tiles, 0);
+        let outside = [
+            Constraint::Connected { sockets: vec![1], endpoints: vec![[0, 0, 0], [9, 9, 0]] },
+            Constraint::Path { sockets: vec![1], endpoints: vec![[6, 0, 0]], min: 0, max: usize::MAX },
+            Constraint::PreservePath { route: vec![[5, 5, 0], [5, 6, 0]], roads: vec![1], walls: vec![] },
+            Constraint::Restrict(Region::Box([0, 0, 0], [5, 5, 1]), TileFilter::Indices(vec![2])),
+            Constraint::Restrict(Region::Mask(vec![true; 35]), TileFilter::Indices(vec![2])),
+        ];
+        for constraint in outside {
+            assert!(wfc.add_constraint(constraint.clone()).is_err(), "{:?}", constraint);
+        }
+        assert!(wfc.constraints.is_empty());
+        wfc.add_constraint(Constraint::Restrict(Region::Mask(vec![true; 36]), TileFilter::Indices(vec![2]))).unwrap();
+    }
+
+    #[test]
+    fn connects_network() {
+        let (_, tiles, _) = pipes();
+        let endpoints = vec![[0, 0, 0], [5, 5, 0]];
+        for seed in 0..5 {
+            let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), tiles.clone(), seed);
+            wfc.add_constraint(Constraint::Connected { sockets: vec![1], endpoints: endpoints.clone() }).unwrap();
+            wfc.run().unwrap();
+            let networks = paths::trace(&wfc.worldmap, &[1]);
+            assert_eq!(networks.len(), 1, "seed {}", seed);
+            assert!(endpoints.iter().all(|x| networks[0].squares.contains(x)), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn path_prunes_endpoints() {
+        let (_, tiles, _) = pipes();
+        let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), tiles, 0);
+        let (start, end) = ([0, 0, 0], [5, 0, 0]);
+        wfc.add_constraint(Constraint::Path { sockets: vec![1], endpoints: vec![start, end], min: 0, max: 5 }).unwrap();
+        // only the straight route along the top edge is short enough
+        for square in [start, end] {
+            let side: usize = if square == start { Direction::EAST.into() } else { Direction::WEST.into() };
+            assert!(wfc.worldmap[square].iter().all(|tile| tile.connection_types[side] == 1), "{:?}", square);
+        }
+        wfc.run().unwrap();
+        let route = paths::trace_from(&wfc.worldmap, &[1], start).route(start, end).unwrap();
+        assert_eq!(route.len(), 6);
+    }
+
+    /// Every face between consecutive route squares carries `roads`, every other face between
+    /// neighbouring route squares carries `walls`.
+    fn assert_route_preserved(worldmap: &Worldmap, route: &[Position], roads: &[usize], walls: &[usize]) {
+        for (i, &square) in route.iter().enumerate() {
+            let tile = worldmap[square][0];
+            for (j, &other) in route.iter().enumerate() {
+                if let Some(dir) = paths::direction_between(worldmap, square, other) {
+                    let sockets = if i.abs_diff(j) == 1 { roads } else { walls };
+                    let side: usize = dir.into();
+                    assert!(sockets.contains(&tile.connection_types[side]), "{:?} towards {:?}", square, other);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn preserves_path() {
+        let (_, tiles, _) = flat_city();
+        let tags = flat_city_tags();
+        let (roads, walls) = (tags.sockets_tagged("road"), tags.sockets_tagged("wall"));
+        let mut wfc = WFC::init(Worldmap::new3d(8, 8, 1), tiles.clone(), 2);
+        wfc.run().unwrap();
+        let network = paths::trace(&wfc.worldmap, &roads).into_iter().max_by_key(|x| x.squares.len()).unwrap();
+        let from = network.squares[0];
+        let route = network.squares.iter().filter_map(|&x| network.route(from, x)).max_by_key(|x| x.len()).unwrap();
+        assert!(route.len() > 2);
+
+        // the route turns back on itself, so its first and last squares face each other
+        let bent = vec![[1, 1, 0], [2, 1, 0], [2, 2, 0], [1, 2, 0]];
+        for route in [route, bent] {
+            let mut wfc = WFC::init(Worldmap::new3d(8, 8, 1), tiles.clone(), 5);
+            wfc.tags = tags.clone();
+            let constraint = Constraint::PreservePath { route: route.clone(), roads: roads.clone(), walls: walls.clone() };
+            wfc.add_constraint(constraint).unwrap();
+            wfc.run().unwrap();
+            assert_route_preserved(&wfc.worldmap, &route, &roads, &walls);
+        }
+
+        let mut wfc = WFC::init(Worldmap::new3d(8, 8, 1), tiles, 5);
+        let broken = Constraint::PreservePath { route: vec![[1, 1, 0], [3, 1, 0]], roads, walls };
+        assert!(wfc.add_constraint(broken).is_err());
+        assert!(wfc.constraints.is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::fmt;
+use crate::wfc::{WfcTile, Direction, Position, WFC};
+use crate::constraints::Constraint;
+
+/// Squares of an `Explanation` besides the failed one, so long propagation chains stay readable.
+const MAX_EXPLAINED_SQUARES: usize = 16;
+
+/// Why tiles were removed from a square.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cause {
+    /// square was collapsed to the tile
+    Collapse(WfcTile),
+    /// neighbour `from` only had `sockets` left on its side `side` facing the square
+    Neighbour { from: Position, side: usize, sockets: Vec<usize> },
+    /// a constraint or a ban removed tiles
+    Restriction,
+}
+
+/// Stack of `square` was reduced to `left` tiles because of `cause`, recorded when `WFC::explain` is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reduction {
+    pub square: Position,
+    pub cause: Cause,
+    pub left: usize,
+}
+
+/// Chain of reductions which emptied the stack of `square`: reductions of the square itself
+/// first, followed by reductions of the neighbours which caused them, and so on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Explanation {
+    pub square: Position,
+    pub reductions: Vec<Reduction>,
+}
+
+impl Explanation {
+    /// Picks reductions leading to `square` from the log of the current step.
+    pub fn new(log: &[Reduction], square: Position) -> Explanation {
+        let mut reductions = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue = vec![square];
+        visited.insert(square);
+        let mut next = 0;
+        while next < queue.len() && visited.len() <= MAX_EXPLAINED_SQUARES + 1 {
+            let current = queue[next];
+            next += 1;
+            for reduction in log.iter().filter(|x| x.square == current) {
+                if let Cause::Neighbour { from, .. } = reduction.cause {
+                    if visited.insert(from) {
+                        queue.push(from);
+                    }
+                }
+                reductions.push(reduction.clone());
+            }
+        }
+        Explanation { square, reductions }
+    }
+
+    fn describe(cause: &Cause) -> String {
+        match cause {
+            Cause::Collapse(tile) => format!("it was collapsed to tile {} ({}°)", tile.index, tile.angle),
+            Cause::Neighbour { from, side, sockets } => {
+                let sockets: Vec<String> = sockets.iter().map(|x| x.to_string()).collect();
+                let plural = if sockets.len() == 1 { "" } else { "s" };
+                format!("{:?} forced socket{} {} {}", from, plural, sockets.join(","), Direction::from(*side).name())
+            },
+            Cause::Restriction => "a constraint removed some".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut squares: Vec<Position> = Vec::new();
+        for reduction in &self.reductions {
+            if !squares.contains(&reduction.square) {
+                squares.push(reduction.square);
+            }
+        }
+        for (i, &square) in squares.iter().enumerate() {
+            let reductions: Vec<&Reduction> = self.reductions.iter().filter(|x| x.square == square).collect();
+            let causes: Vec<String> = reductions.iter().map(|x| Explanation::describe(&x.cause)).collect();
+            let left = reductions.last().map_or(0, |x| x.left);
+            if i == 0 && left == 0 {
+                write!(f, "cell {:?} lost all tiles because {}", square, causes.join(" and "))?;
+            } else {
+                write!(f, "\n  {:?} was left with {} tile(s) because {}", square, left, causes.join(" and "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Part of a setup which `minimal_conflict` may leave out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Requirement {
+    Tile(Position, WfcTile),
+    Constraint(Constraint),
+}
+
+fn conflicts(base: &WFC, requirements: &[&Requirement], solve: bool) -> bool {
+    let mut wfc = base.clone();
+    for requirement in requirements {
+        let result = match requirement {
+            Requirement::Tile(square, tile) => wfc.add_tile(*square, *tile),
+            Requirement::Constraint(constraint) => wfc.add_constraint(constraint.clone()),
+        };
+        if result.is_err() {
+            return true;
+        }
+    }
+    solve && wfc.run().is_err()
+}
+
+/// Finds a set of `requirements` which can't be met together on `base` (a solver before setup),
+/// such that leaving out any one of them resolves the conflict.
+///
+/// Without `solve` a set conflicts only if propagation alone finds a contradiction, which is fast
+/// but misses conflicts found by search. With `solve` the solver is run too, so a set also counts
+/// as conflicting when it merely runs out of `backtrack_limit`.
+///
+/// Returns `None` if all requirements together don't conflict.
+pub fn minimal_conflict(base: &WFC, requirements: &[Requirement], solve: bool) -> Option<Vec<Requirement>> {
+    let mut conflict: Vec<&Requirement> = requirements.iter().collect();
+    if !conflicts(base, &conflict, solve) {
+        return None;
+    }
+    let mut i = 0;
+    while i < conflict.len() {
+        let mut rest = conflict.clone();
+        rest.remove(i);
+        if conflicts(base, &rest, solve) {
+            conflict = rest;
+        } else {
+            i += 1;
+        }
+    }
+    Some(conflict.into_iter().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wfc::Worldmap;
+    use crate::constraints::TileFilter;
+    use crate::tilesets::pipes;
+
+    #[test]
+    fn finds_minimal_conflict() {
+        let (_, tiles, _) = pipes();
+        let (empty, line) = (tiles[1], tiles[2]);
+        let base = WFC::init(Worldmap::new3d(6, 6, 1), tiles.clone(), 0);
+        // a horizontal line can't continue into an empty square
+        let requirements = vec![
+            Requirement::Tile([0, 0, 0], empty),
+            Requirement::Tile([5, 5, 0], line),
+            Requirement::Constraint(Constraint::at_most(TileFilter::Indices(vec![tiles[3].index]), 4)),
+            Requirement::Tile([1, 0, 0], line),
+        ];
+        let conflict = minimal_conflict(&base, &requirements, false).unwrap();
+        assert_eq!(conflict, [requirements[0].clone(), requirements[3].clone()]);
+        assert_eq!(minimal_conflict(&base, &requirements[..3], true), None);
+
+        let mut wfc = base.clone();
+        wfc.explain = true;
+        wfc.add_tile([0, 0, 0], empty).unwrap();
+        let error = wfc.add_tile([1, 0, 0], line).unwrap_err();
+        assert!(error.contains("lost all tiles because"), "{}", error);
+        let explanation = wfc.contradiction.unwrap();
+        assert!(explanation.reductions.iter().any(|x| x.cause == Cause::Collapse(line)));
+    }
+}
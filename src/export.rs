@@ -0,0 +1,233 @@
+use std::fs::File;
+use std::io::BufWriter;
+use crate::wfc::{WfcTile, Worldmap};
+use crate::rng;
+
+/// Worldmap as text, one block of rows per layer with `index/angle` for every square,
+/// `.` for masked-out squares and `?` for squares which aren't collapsed.
+pub fn to_text(worldmap: &Worldmap) -> String {
+    let [x_size, y_size, z_size] = worldmap.size;
+    let mut rv = String::new();
+    for z in 0..z_size {
+        if z_size > 1 {
+            rv += &format!("# layer {}\n", z);
+        }
+        for y in 0..y_size {
+            let row: Vec<String> = (0..x_size).map(|x| {
+                let stack = &worldmap[(x, y, z)];
+                match stack.len() {
+                    _ if !worldmap.is_active([x, y, z]) => ".".to_string(),
+                    1 => format!("{}/{}", stack[0].index, stack[0].angle),
+                    _ => "?".to_string(),
+                }
+            }).collect();
+            rv += &row.join(" ");
+            rv += "\n";
+        }
+    }
+    rv
+}
+
+/// RGBA image, 4 bytes per pixel row by row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Image {
+        Image { width, height, pixels: vec![0; (width * height * 4) as usize] }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * self.width + x) * 4) as usize;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: [u8; 4]) {
+        let i = ((y * self.width + x) * 4) as usize;
+        self.pixels[i..i + 4].copy_from_slice(&color);
+    }
+
+    pub fn load_png(path: &str) -> Result<Image, String> {
+        let file = File::open(path).map_err(|e| format!("error: can't open {}: {}", path, e))?;
+        let mut decoder = png::Decoder::new(file);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|e| format!("error: can't read {}: {}", path, e))?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(|e| format!("error: can't read {}: {}", path, e))?;
+
+        let mut image = Image::new(info.width, info.height);
+        let channels = info.color_type.samples();
+        for (i, pixel) in buffer[..info.buffer_size()].chunks(channels).enumerate() {
+            let color = match *pixel {
+                [v] => [v, v, v, 255],
+                [v, a] => [v, v, v, a],
+                [r, g, b] => [r, g, b, 255],
+                [r, g, b, a] => [r, g, b, a],
+                _ => return Err(format!("error: unsupported colors in {}", path)),
+            };
+            image.pixels[i * 4..i * 4 + 4].copy_from_slice(&color);
+        }
+        Ok(image)
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| format!("error: can't create {}: {}", path, e))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.pixels))
+            .map_err(|e| format!("error: can't write {}: {}", path, e))
+    }
+}
+
+/// Tilemap image cut into square tiles, tile `index` counts tiles row by row.
+pub struct Atlas {
+    pub image: Image,
+    pub tile_size: u32,
+    pub columns: u32,
+}
+
+impl Atlas {
+    /// Loads tilemap with `columns` tiles per row, tile size is the image width divided by `columns`.
+    pub fn load(path: &str, columns: u32) -> Result<Atlas, String> {
+        let image = Image::load_png(path)?;
+        let tile_size = image.width / columns;
+        if tile_size == 0 {
+            return Err(format!("error: {} is narrower than {} tiles", path, columns));
+        }
+        Ok(Atlas { image, tile_size, columns })
+    }
+
+    /// Whether the image is big enough to have `tile`.
+    pub fn has(&self, tile: &WfcTile) -> bool {
+        (tile.index / self.columns + 1) * self.tile_size <= self.image.height
+    }
+}
+
+/// How a tile is turned when drawn, clockwise like in the viewer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Orientation {
+    pub angle: u32,
+    /// mirrored left to right before rotating, tiles can't be flipped yet
+    pub is_flipped: bool,
+}
+
+impl From<&WfcTile> for Orientation {
+    fn from(tile: &WfcTile) -> Orientation {
+        Orientation { angle: tile.angle, is_flipped: false }
+    }
+}
+
+impl Orientation {
+    /// Pixel of an unturned tile of `size` which ends up at `x, y`.
+    pub fn source(&self, x: u32, y: u32, size: u32) -> (u32, u32) {
+        let last = size - 1;
+        let (x, y) = match self.angle % 360 {
+            90 => (y, last - x),
+            180 => (last - x, last - y),
+            270 => (last - y, x),
+            _ => (x, y),
+        };
+        if self.is_flipped { (last - x, y) } else { (x, y) }
+    }
+}
+
+const UNCOLLAPSED_COLOR: [u8; 4] = [135, 135, 135, 255];
+
+/// Colour standing in for a tile when there is no tilemap image.
+pub fn tile_color(tile: &WfcTile) -> [u8; 4] {
+    let hash = rng::mix(tile.index as u64);
+    [64 + (hash & 127) as u8, 64 + (hash >> 8 & 127) as u8, 64 + (hash >> 16 & 127) as u8, 255]
+}
+
+/// Draws layer `z` of `worldmap` with tiles from `atlas`, or as plain squares of `tile_size`
+/// coloured by tile index without one (or for tiles missing from it). Uncollapsed squares are grey, masked-out ones transparent.
+pub fn render_layer(worldmap: &Worldmap, z: usize, atlas: Option<&Atlas>, tile_size: u32) -> Image {
+    let tile_size = atlas.map_or(tile_size, |x| x.tile_size);
+    let [x_size, y_size, _] = worldmap.size;
+    let mut image = Image::new(x_size as u32 * tile_size, y_size as u32 * tile_size);
+    for y in 0..y_size {
+    for x in 0..x_size {
+        let stack = &worldmap[(x, y, z)];
+        if !worldmap.is_active([x, y, z]) {
+            continue;
+        }
+        for dy in 0..tile_size {
+        for dx in 0..tile_size {
+            let color = match (stack.len(), atlas) {
+                (1, Some(atlas)) if atlas.has(&stack[0]) => {
+                    let tile = &stack[0];
+                    let (sx, sy) = Orientation::from(tile).source(dx, dy, tile_size);
+                    let col = tile.index % atlas.columns;
+                    let row = tile.index / atlas.columns;
+                    atlas.image.pixel(col * tile_size + sx, row * tile_size + sy)
+                },
+                (1, _) => tile_color(&stack[0]),
+                _ => UNCOLLAPSED_COLOR,
+            };
+            image.set_pixel(x as u32 * tile_size + dx, y as u32 * tile_size + dy, color);
+        }
+        }
+    }
+    }
+    image
+}
+
+/// One image per layer of `worldmap`, bottom layer first.
+pub fn render_layers(worldmap: &Worldmap, atlas: Option<&Atlas>, tile_size: u32) -> Vec<Image> {
+    (0..worldmap.size[2]).map(|z| render_layer(worldmap, z, atlas, tile_size)).collect()
+}
+
+/// Writes `worldmap` to `path` as PNG, layers of 3d maps go to `<path without .png>_<layer>.png`.
+/// Returns paths of written files.
+pub fn save_png(worldmap: &Worldmap, atlas: Option<&Atlas>, tile_size: u32, path: &str) -> Result<Vec<String>, String> {
+    let layers = render_layers(worldmap, atlas, tile_size);
+    if layers.len() == 1 {
+        layers[0].save_png(path)?;
+        return Ok(vec![path.to_string()]);
+    }
+    let stem = path.strip_suffix(".png").unwrap_or(path);
+    let mut paths = Vec::new();
+    for (z, layer) in layers.iter().enumerate() {
+        let path = format!("{}_{}.png", stem, z);
+        layer.save_png(&path)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_rotated_tiles() {
+        // 2x2 tile with a red top-left pixel next to a plain tile
+        let mut image = Image::new(4, 2);
+        image.set_pixel(0, 0, [255, 0, 0, 255]);
+        let atlas = Atlas { image, tile_size: 2, columns: 2 };
+
+        let mut worldmap = Worldmap::new3d(4, 1, 1);
+        let tile = WfcTile { index: 0, connection_types: [0; 6], angle: 0, is_rotatable: true };
+        for (x, angle) in [0, 90, 180, 270].iter().enumerate() {
+            worldmap[(x, 0)] = vec![WfcTile { angle: *angle, ..tile }];
+        }
+        let image = render_layer(&worldmap, 0, Some(&atlas), 0);
+        let red: Vec<(u32, u32)> = (0..8).flat_map(|x| (0..2).map(move |y| (x, y)))
+            .filter(|&(x, y)| image.pixel(x, y)[0] == 255)
+            .collect();
+        // rotated clockwise: top-left, top-right, bottom-right, bottom-left
+        assert_eq!(red, [(0, 0), (3, 0), (5, 1), (6, 1)]);
+
+        let path = std::env::temp_dir().join("wfc-export-test.png");
+        let path = path.to_str().unwrap();
+        image.save_png(path).unwrap();
+        assert_eq!(Image::load_png(path).unwrap(), image);
+        std::fs::remove_file(path).unwrap();
+    }
+}
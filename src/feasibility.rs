@@ -0,0 +1,131 @@
+use crate::wfc::{WfcTile, Worldmap, WFC};
+
+/// Result of `check`: what arc consistency alone can tell about a prepared solver.
+#[derive(Clone)]
+pub struct Feasibility {
+    /// stacks after arc consistency, every tile in them has a matching neighbour on every side
+    pub domains: Worldmap,
+    /// variants (rotations included) left in at least one square
+    pub possible: Vec<WfcTile>,
+    /// indexes of tiles which can't appear anywhere on the map
+    pub impossible: Vec<u32>,
+    /// contradiction found while enforcing consistency, the map can't be filled at all
+    pub contradiction: Option<String>,
+}
+
+impl Feasibility {
+    /// False only if the map is certainly infeasible, true doesn't guarantee a solution (see `exhaustive`).
+    pub fn is_possible(&self) -> bool {
+        self.contradiction.is_none()
+    }
+}
+
+/// Propagates every square of `wfc` (a solver after setup, e.g. with border tiles added) and
+/// applies its constraints until nothing changes, without collapsing anything.
+fn make_consistent(wfc: &mut WFC) -> Result<(), String> {
+    loop {
+        let before: usize = wfc.squares_list.iter().map(|&x| wfc.worldmap[x].len()).sum();
+        for square in wfc.squares_list.clone() {
+            if !wfc.worldmap.is_active(square) {
+                continue;
+            }
+            if wfc.worldmap[square].is_empty() {
+                return Err(format!("error: no tiles left for square {:?}", square));
+            }
+            wfc.propagate(square)?;
+        }
+        wfc.apply_constraints()?;
+        let after: usize = wfc.squares_list.iter().map(|&x| wfc.worldmap[x].len()).sum();
+        if after == before {
+            return Ok(());
+        }
+    }
+}
+
+/// Computes by arc consistency which tiles can appear on the map of `wfc` and whether it is
+/// trivially infeasible. Cheap, works for any map size.
+pub fn check(wfc: &WFC) -> Feasibility {
+    let mut solver = wfc.clone();
+    let contradiction = make_consistent(&mut solver).err();
+
+    let mut possible: Vec<WfcTile> = Vec::new();
+    let squares = if contradiction.is_none() { solver.squares_list.as_slice() } else { &[] };
+    for &square in squares {
+        for tile in &solver.worldmap[square] {
+            if !possible.contains(tile) {
+                possible.push(*tile);
+            }
+        }
+    }
+    let mut impossible: Vec<u32> = Vec::new();
+    for tile in &wfc.tiles {
+        if !possible.iter().any(|x| x.index == tile.index) && !impossible.contains(&tile.index) {
+            impossible.push(tile.index);
+        }
+    }
+    Feasibility { domains: solver.worldmap, possible, impossible, contradiction }
+}
+
+fn search(wfc: WFC) -> Option<Worldmap> {
+    let square = wfc.squares_list.iter()
+        .filter(|&&x| wfc.worldmap.is_active(x) && wfc.worldmap[x].len() > 1)
+        .min_by_key(|&&x| wfc.worldmap[x].len())
+        .cloned();
+    let square = match square {
+        Some(x) => x,
+        None => return Some(wfc.worldmap),
+    };
+    for tile in wfc.worldmap[square].clone() {
+        let mut solver = wfc.clone();
+        solver.set_stack(square, vec![tile]);
+        if solver.propagate(square).is_err() || make_consistent(&mut solver).is_err() {
+            continue;
+        }
+        if let Some(worldmap) = search(solver) {
+            return Some(worldmap);
+        }
+    }
+    None
+}
+
+/// Tries every combination of tiles (pruned by arc consistency) instead of random choices,
+/// so unlike running the solver it also proves a map can't be filled.
+/// Returns a solution or `None` if there is none, refuses maps with more than `max_squares` squares.
+pub fn exhaustive(wfc: &WFC, max_squares: usize) -> Result<Option<Worldmap>, String> {
+    let squares = wfc.squares_list.iter().filter(|&&x| wfc.worldmap.is_active(x)).count();
+    if squares > max_squares {
+        return Err(format!("error: map has {} squares, exhaustive check is limited to {}", squares, max_squares));
+    }
+    let mut solver = wfc.clone();
+    if make_consistent(&mut solver).is_err() {
+        return Ok(None);
+    }
+    Ok(search(solver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilesets::pipes;
+
+    #[test]
+    fn finds_possible_tiles() {
+        let (_, tiles, _) = pipes();
+        let empty = tiles[1];
+
+        // a 3x3 box with empty border only has room for an empty tile in the middle
+        let mut wfc = WFC::init(Worldmap::new3d(3, 3, 1), tiles.clone(), 0);
+        wfc.surround_worldmap_2d(&empty).unwrap();
+        let feasibility = check(&wfc);
+        assert!(feasibility.is_possible());
+        assert_eq!(feasibility.possible, [empty]);
+        assert!(exhaustive(&wfc, 9).unwrap().is_some());
+        assert!(exhaustive(&wfc, 8).is_err());
+
+        let mut wfc = WFC::init(Worldmap::new3d(10, 10, 1), tiles.clone(), 0);
+        wfc.surround_worldmap_2d(&empty).unwrap();
+        let feasibility = check(&wfc);
+        assert!(feasibility.is_possible());
+        assert_eq!(feasibility.impossible, []);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::wfc::{WfcTile, Worldmap, Position, WFC};
+use crate::constraints::{Region, TileFilter, Tags};
+
+/// How the fine worldmap is solved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefineMode {
+    /// All fine squares are collapsed in worldmap order.
+    Global,
+    /// Blocks are solved one after another (in coarse worldmap order), every block is finished
+    /// before the next one starts and its border only has to agree with blocks done before.
+    PerBlock,
+}
+
+/// Refines a solved coarse worldmap (biomes, districts, ...) into a fine one, where every coarse
+/// square becomes a block of fine squares restricted to tiles allowed for the coarse tile.
+/// Neighbouring blocks are solved on a single fine worldmap, so sockets along block borders always agree.
+#[derive(Clone, Debug)]
+pub struct Hierarchy {
+    /// fine squares per coarse square along x, y and z
+    pub block: [usize; 3],
+    /// fine tiles allowed for coarse tiles matching the first filter, first match wins;
+    /// coarse tiles without a match allow every fine tile
+    pub refinements: Vec<(TileFilter, TileFilter)>,
+    pub mode: RefineMode,
+}
+
+impl Hierarchy {
+    pub fn new(block: [usize; 3], mode: RefineMode) -> Hierarchy {
+        Hierarchy {
+            block,
+            refinements: Vec::new(),
+            mode,
+        }
+    }
+
+    /// Allows only `fine` tiles inside blocks of coarse tiles matching `coarse`.
+    pub fn refine(&mut self, coarse: TileFilter, fine: TileFilter) -> &mut Self {
+        self.refinements.push((coarse, fine));
+        self
+    }
+
+    /// Coarse square covering `square` of the fine worldmap.
+    pub fn coarse_square(&self, square: Position) -> Position {
+        [square[0] / self.block[0], square[1] / self.block[1], square[2] / self.block[2]]
+    }
+
+    /// Fine squares covered by `coarse_square`.
+    pub fn block_region(&self, coarse_square: Position) -> Region {
+        let from = [0, 1, 2].map(|i| coarse_square[i] * self.block[i]);
+        let to = [0, 1, 2].map(|i| from[i] + self.block[i] - 1);
+        Region::Box(from, to)
+    }
+
+    /// Fine worldmap covering `coarse`, masked-out coarse squares mask out their whole block.
+    /// Mask sockets and boundaries are copied from `coarse`.
+    pub fn fine_worldmap(&self, coarse: &Worldmap) -> Worldmap {
+        let [x, y, z] = coarse.size;
+        let mut worldmap = Worldmap::with_mask(x * self.block[0], y * self.block[1], z * self.block[2],
+            |square| coarse.is_active(self.coarse_square(square)));
+        worldmap.set_mask_sockets(coarse.mask_sockets.clone());
+        worldmap.boundaries = coarse.boundaries.clone();
+        worldmap
+    }
+
+    fn filter_for(&self, tags: &Tags, coarse_tile: &WfcTile) -> Option<&TileFilter> {
+        self.refinements.iter()
+            .find(|(coarse, _)| coarse.matches(tags, coarse_tile))
+            .map(|(_, fine)| fine)
+    }
+
+    /// Creates fine solver for a solved `coarse` solver, restricting every block to its allowed tiles.
+    pub fn init(&self, coarse: &WFC, tiles: Vec<WfcTile>, tags: Tags, seed: u64) -> Result<WFC, String> {
+        let mut wfc = WFC::init(self.fine_worldmap(&coarse.worldmap), tiles, seed);
+        wfc.tags = tags;
+        self.apply(coarse, &mut wfc)?;
+        Ok(wfc)
+    }
+
+    /// Blocks of active coarse squares in coarse worldmap order, checking that `coarse` is solved.
+    fn blocks(&self, coarse: &WFC) -> Result<Vec<(Position, Region)>, String> {
+        let mut blocks = Vec::new();
+        for &coarse_square in &coarse.squares_list {
+            if !coarse.worldmap.is_active(coarse_square) {
+                continue;
+            }
+            if coarse.worldmap[coarse_square].len() != 1 {
+                return Err(format!("error: coarse square {:?} is not collapsed", coarse_square));
+            }
+            blocks.push((coarse_square, self.block_region(coarse_square)));
+        }
+        Ok(blocks)
+    }
+
+    /// Restricts blocks of the fine solver `wfc` according to the solved `coarse` solver.
+    /// Restrictions are applied once before solving, they don't become constraints of `wfc`.
+    pub fn apply(&self, coarse: &WFC, wfc: &mut WFC) -> Result<(), String> {
+        let expected = [0, 1, 2].map(|i| coarse.worldmap.size[i] * self.block[i]);
+        if wfc.worldmap.size != expected {
+            return Err(format!("error: fine worldmap size {:?} doesn't match coarse worldmap, expected {:?}", wfc.worldmap.size, expected));
+        }
+
+        let tags = wfc.tags.clone();
+        for (coarse_square, region) in self.blocks(coarse)? {
+            let filter = match self.filter_for(&coarse.tags, &coarse.worldmap[coarse_square][0]) {
+                Some(x) => x.clone(),
+                None => continue,
+            };
+            for square in region.squares(&wfc.worldmap) {
+                wfc.retain(square, |tile| filter.matches(&tags, tile))
+                    .map_err(|e| format!("error: can't refine coarse square {:?}: {}", coarse_square, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Solves the fine solver `wfc` prepared by `apply` according to `mode`. In `PerBlock` mode
+    /// a finished block is kept as it is, backtracking only takes back steps of the block being solved,
+    /// and steps can't be undone afterwards.
+    pub fn solve(&self, coarse: &WFC, wfc: &mut WFC) -> Result<(), String> {
+        if self.mode == RefineMode::Global {
+            return wfc.run().map(|_| ());
+        }
+        let squares_list = wfc.squares_list.clone();
+        for (coarse_square, region) in self.blocks(coarse)? {
+            wfc.squares_list = region.squares(&wfc.worldmap);
+            wfc.squares_index = 0;
+            wfc.steps.clear();
+            wfc.trail.clear();
+            wfc.undone.clear();
+            if let Err(e) = wfc.run() {
+                wfc.squares_list = squares_list;
+                return Err(format!("error: can't solve block of coarse square {:?}: {}", coarse_square, e));
+            }
+        }
+        wfc.squares_list = squares_list;
+        wfc.squares_index = wfc.squares_list.len();
+        wfc.steps.clear();
+        wfc.trail.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilesets::pipes;
+
+    #[test]
+    fn refines_coarse_worldmap() {
+        let (_, tiles, _) = pipes();
+        let empty = tiles[1];
+        let mut coarse = WFC::init(Worldmap::new3d(3, 3, 1), tiles.clone(), 0);
+        coarse.run().unwrap();
+        let empty_blocks: Vec<Position> = coarse.squares_list.iter()
+            .filter(|&&x| coarse.worldmap[x][0].index == empty.index)
+            .cloned()
+            .collect();
+
+        for mode in [RefineMode::Global, RefineMode::PerBlock] {
+            let mut hierarchy = Hierarchy::new([3, 3, 1], mode);
+            hierarchy.refine(TileFilter::Indices(vec![empty.index]), TileFilter::Indices(vec![empty.index]));
+            let mut wfc = hierarchy.init(&coarse, tiles.clone(), Tags::default(), 0).unwrap();
+            assert!(wfc.constraints.is_empty());
+            hierarchy.solve(&coarse, &mut wfc).unwrap();
+            for square in wfc.squares_list.clone() {
+                assert_eq!(wfc.worldmap[square].len(), 1, "{:?}", mode);
+                if empty_blocks.contains(&hierarchy.coarse_square(square)) {
+                    assert_eq!(wfc.worldmap[square][0].index, empty.index, "{:?}", mode);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,15 @@
+pub mod wfc;
+pub mod constraints;
+pub mod explain;
+pub mod export;
+pub mod feasibility;
+pub mod lint;
+pub mod paths;
+pub mod rng;
+pub mod save;
+pub mod search;
+pub mod setup;
+pub mod pipeline;
+pub mod hierarchy;
+pub mod chunks;
+pub mod tilesets;
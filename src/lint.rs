@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::wfc::{WfcTile, Direction, WFC, GENERATED_CONNECTIONS};
+use crate::tilesets::Tileset;
+
+/// Problem found in a tileset by `Tileset::validate`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    /// No tile has `socket` on the side opposite to `side`, so nothing can be placed next to it.
+    UnmatchedSocket { socket: usize, side: usize },
+    /// Tile can't be placed anywhere away from the map edges.
+    NeverPlaced { index: u32 },
+    /// Two variants have the same sockets, `first == second` for rotations of one tile.
+    DuplicateVariant { first: (u32, u32), second: (u32, u32) },
+    /// Big-tile socket appears on more than one tile side, so big tile parts get mixed up.
+    BigTileClash { socket: usize, side: usize, count: usize },
+    /// Big-tile socket has no counterpart, so the big tile can't be completed.
+    BigTileBroken { socket: usize, side: usize },
+    /// Rotated tile has a vertical socket no rotation of another tile matches.
+    MissingVerticalRotation { index: u32, angle: u32, socket: usize, side: usize },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::UnmatchedSocket { socket, side } =>
+                write!(f, "socket {} on {} side has no match on {} side", socket, Direction::from(*side).name(), Direction::from(opposite(*side)).name()),
+            Issue::NeverPlaced { index } =>
+                write!(f, "tile {} can never be placed away from the map edges", index),
+            Issue::DuplicateVariant { first, second } if first.0 == second.0 =>
+                write!(f, "tile {} has identical rotations {}° and {}°", first.0, first.1, second.1),
+            Issue::DuplicateVariant { first, second } =>
+                write!(f, "tiles {} ({}°) and {} ({}°) have identical sockets", first.0, first.1, second.0, second.1),
+            Issue::BigTileClash { socket, side, count } =>
+                write!(f, "big-tile socket {} is on {} side of {} tiles", socket, Direction::from(*side).name(), count),
+            Issue::BigTileBroken { socket, side } =>
+                write!(f, "big-tile socket {} on {} side has no counterpart", socket, Direction::from(*side).name()),
+            Issue::MissingVerticalRotation { index, angle, socket, side } =>
+                write!(f, "tile {} rotated {}° has {} socket {} which no tile matches", index, angle, Direction::from(*side).name(), socket),
+        }
+    }
+}
+
+fn opposite(side: usize) -> usize {
+    Direction::from(side).flip().into()
+}
+
+impl Tileset {
+    /// Lints the tileset, returns nothing if no problems were found.
+    pub fn validate(&self) -> Vec<Issue> {
+        let variants = WFC::variants(&self.tiles);
+        let mut issues = Vec::new();
+
+        // how many variants have each socket on each side
+        let mut sockets: [HashMap<usize, usize>; 6] = Default::default();
+        for tile in &variants {
+            for (side_sockets, &socket) in sockets.iter_mut().zip(&tile.connection_types) {
+                *side_sockets.entry(socket).or_default() += 1;
+            }
+        }
+
+        for side in 0..6 {
+            let mut side_sockets: Vec<(&usize, &usize)> = sockets[side].iter().collect();
+            side_sockets.sort();
+            for (&socket, &count) in side_sockets {
+                let is_matched = sockets[opposite(side)].contains_key(&socket);
+                if socket < GENERATED_CONNECTIONS {
+                    if !is_matched {
+                        issues.push(Issue::UnmatchedSocket { socket, side });
+                    }
+                    continue;
+                }
+                let is_vertical = side >= 4;
+                let rotated = variants.iter().find(|tile| tile.is_rotatable && tile.connection_types[side] == socket);
+                match rotated {
+                    Some(tile) if is_vertical && !is_matched => issues.push(Issue::MissingVerticalRotation {
+                        index: tile.index, angle: tile.angle, socket, side,
+                    }),
+                    Some(_) => (),
+                    None if !is_matched => issues.push(Issue::BigTileBroken { socket, side }),
+                    None if count > 1 => issues.push(Issue::BigTileClash { socket, side, count }),
+                    None => (),
+                }
+            }
+        }
+
+        issues.extend(duplicates(&variants));
+        issues.extend(never_placed(&self.tiles, &variants));
+        issues
+    }
+}
+
+fn duplicates(variants: &[WfcTile]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for (i, first) in variants.iter().enumerate() {
+        for second in &variants[i+1..] {
+            if first.connection_types == second.connection_types {
+                issues.push(Issue::DuplicateVariant { first: (first.index, first.angle), second: (second.index, second.angle) });
+            }
+        }
+    }
+    issues
+}
+
+/// Removes variants which can't have a neighbour on some side until none is left to remove,
+/// tiles without any remaining variant can only be placed along map edges.
+fn never_placed(tiles: &[WfcTile], variants: &[WfcTile]) -> Vec<Issue> {
+    let mut is_possible = vec![true; variants.len()];
+    loop {
+        let mut is_changed = false;
+        for i in 0..variants.len() {
+            if !is_possible[i] {
+                continue;
+            }
+            let is_supported = (0..6).all(|side| {
+                let socket = variants[i].connection_types[side];
+                variants.iter().enumerate().any(|(j, x)| is_possible[j] && x.connection_types[opposite(side)] == socket)
+            });
+            if !is_supported {
+                is_possible[i] = false;
+                is_changed = true;
+            }
+        }
+        if !is_changed {
+            break;
+        }
+    }
+
+    let mut issues: Vec<Issue> = Vec::new();
+    for tile in tiles {
+        let is_placeable = variants.iter().zip(&is_possible).any(|(x, &possible)| possible && x.index == tile.index);
+        let issue = Issue::NeverPlaced { index: tile.index };
+        if !is_placeable && !issues.contains(&issue) {
+            issues.push(issue);
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilesets::{tileset, TILESET_NAMES};
+    use crate::constraints::Tags;
+
+    #[test]
+    fn shipped_tilesets_are_valid() {
+        // flat_city has several plain grass tiles on purpose
+        let duplicate = |first, second| Issue::DuplicateVariant { first: (first, 0), second: (second, 0) };
+        for name in TILESET_NAMES {
+            let expected = match name {
+                "flat_city" => vec![duplicate(0, 1), duplicate(0, 4), duplicate(1, 4)],
+                _ => vec![],
+            };
+            assert_eq!(tileset(name).unwrap().validate(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn finds_broken_tiles() {
+        let tile = |index, connection_types, is_rotatable| WfcTile { index, connection_types, angle: 0, is_rotatable };
+        let tileset = Tileset {
+            name: "broken".to_string(),
+            image: String::new(),
+            tiles: vec![
+                tile(0, [0, 0, 0, 0, 0, 0], false),
+                tile(1, [2, 0, 0, 0, 0, 0], false),
+                tile(2, [1, 1, 1, 1, 0, 0], true),
+                tile(3, [1, 1, 1, 1, 0, 0], false),
+            ],
+            tilemap_size: 4,
+            tags: Tags::default(),
+        };
+        let issues = tileset.validate();
+        assert!(issues.contains(&Issue::UnmatchedSocket { socket: 2, side: 0 }));
+        assert!(issues.contains(&Issue::NeverPlaced { index: 1 }));
+        assert!(issues.contains(&Issue::DuplicateVariant { first: (2, 0), second: (3, 0) }));
+    }
+}
@@ -9,28 +9,113 @@ use sdl2::video::Window;
 use sdl2::gfx::primitives::DrawRenderer;
 use std::time::Duration;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
 use signal_hook::flag;
 
-mod wfc;
-mod tilesets;
-use wfc::*;
-use tilesets::*;
+use sdl_wfc::wfc::*;
+use sdl_wfc::paths;
+use sdl_wfc::export::tile_color;
+use sdl_wfc::constraints::Constraint;
+use sdl_wfc::pipeline::Pipeline;
+use sdl_wfc::search::SeedSearch;
+use sdl_wfc::setup::{apply_setup, parse_args, parse_size, parse_sizes, parse_number};
+use sdl_wfc::tilesets::*;
 
 const SHOW_CONNECTIONS: bool = false;
 const SHOW_TILESET: bool = false;
 const AUTO_TRY: bool = false;
 const STOP_ON_SUCCESS: bool = true;
-const STARTING_SEED: u64 = 204;
+const SAVE_PATH: &str = "wfc.save";
+
+const USAGE: &str = "\
+Interactive wave function collapse viewer.
+
+usage: sdl-wfc [options]
+
+options:
+  --tileset NAME      tileset to generate, without it a road and city demo runs on flat_city
+  --setup FILE        setup file for the tileset (see `wfc help`)
+  --size XxY[xZ]      map size, only the bottom layer is drawn (default: 10x10x1)
+  --seed N            starting seed (default: 204)
+  --scale N           draw tiles N times bigger, by default the map is fitted to the window
+  --tile-size N       tile size in the tilemap image, by default image width / tiles per row
+  --window WxH        window size (default: 1290x720)
+  --font FILE         TTF font for numbers, by default a built-in bitmap font is used
+";
+
+/// Viewer settings, from command line arguments.
+struct Config {
+    tileset: Option<Tileset>,
+    setup: Option<String>,
+    size: [usize; 3],
+    seed: u64,
+    scale: Option<u32>,
+    tile_size: Option<u32>,
+    window: (u32, u32),
+    font: Option<String>,
+}
+
+impl Config {
+    fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut values = parse_args(args)?;
+
+        let tileset = match values.remove("tileset") {
+            Some(name) => Some(tileset(name).ok_or_else(|| format!("error: unknown tileset '{}', one of: {}", name, TILESET_NAMES.join(", ")))?),
+            None => None,
+        };
+        let size = parse_size(values.remove("size").unwrap_or("10x10x1"))?;
+        let window = match parse_sizes("window", values.remove("window").unwrap_or("1290x720"))?[..] {
+            [w, h] => (w as u32, h as u32),
+            _ => return Err("error: window size must be WxH".to_string()),
+        };
+        let config = Config {
+            tileset,
+            setup: values.remove("setup").map(|x| x.to_string()),
+            size,
+            seed: parse_number("seed", values.remove("seed").unwrap_or("204"))?,
+            scale: values.remove("scale").map(|x| parse_number("scale", x)).transpose()?,
+            tile_size: values.remove("tile-size").map(|x| parse_number("tile size", x)).transpose()?,
+            window,
+            font: values.remove("font").map(|x| x.to_string()),
+        };
+        if let Some(name) = values.keys().next() {
+            return Err(format!("error: unknown option --{}", name));
+        }
+        if config.tileset.is_none() && config.setup.is_some() {
+            return Err("error: --setup needs --tileset".to_string());
+        }
+        if config.tileset.is_none() && (config.size[0] < 9 || config.size[1] < 9) {
+            return Err("error: demo needs map of at least 9x9".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// Where tiles are drawn: size of a tile in the tilemap, on screen and top-left corner of the map.
+struct View {
+    tile_size: u32,
+    scaled: u32,
+    offset: (i32, i32),
+}
 
-const TILESIZE: u32 = 8;
-const SCALE: u32 = 8;
-const TILESIZE_SCALED: u32 = TILESIZE * SCALE;
-const WIN_WIDTH: u32 = 1290;
-const WIN_HEIGHT: u32 = 720;
-//const MAP_SIZE: (usize, usize, usize) = ((WIN_WIDTH/TILESIZE/SCALE) as usize, (WIN_HEIGHT/TILESIZE/SCALE) as usize, 1);
-//const MAP_SIZE: (usize, usize, usize) = (20, 20, 20);
-const MAP_SIZE: (usize, usize, usize) = (10, 10, 1);
+impl View {
+    /// Scales tiles by `scale`, or as big as the whole map fits into the window.
+    fn fit(tile_size: u32, scale: Option<u32>, window: (u32, u32), map: (usize, usize)) -> View {
+        let (map_width, map_height) = (map.0 as u32, map.1 as u32);
+        let scaled = match scale {
+            Some(scale) => tile_size * scale,
+            None => std::cmp::min(window.0 / map_width, window.1 / map_height).max(1),
+        };
+        let offset = (
+            (window.0 as i32 - (scaled * map_width) as i32).max(0) / 2,
+            (window.1 as i32 - (scaled * map_height) as i32).max(0) / 2,
+        );
+        View { tile_size, scaled, offset }
+    }
+
+    fn rect(&self, x: u32, y: u32) -> Rect {
+        Rect::new(self.offset.0 + (x*self.scaled) as i32, self.offset.1 + (y*self.scaled) as i32, self.scaled, self.scaled)
+    }
+}
 
 const CON_TYPE_COLORS: [Color;4] = [
     Color::RED,
@@ -39,18 +124,54 @@ const CON_TYPE_COLORS: [Color;4] = [
     Color::BLUE,
 ];
 
+/// 3x5 pixel digits, rows from the top, 3 bits per row.
+const BITMAP_DIGITS: [u16; 10] = [
+    0b111_101_101_101_111,
+    0b010_110_010_010_111,
+    0b111_001_111_100_111,
+    0b111_001_111_001_111,
+    0b101_101_111_001_001,
+    0b111_100_111_001_111,
+    0b111_100_111_101_111,
+    0b111_001_001_001_001,
+    0b111_101_111_101_111,
+    0b111_101_111_001_111,
+];
+
+/// Font for numbers drawn over tiles, the bitmap font needs no font file.
+enum TextFont<'ttf> {
+    Ttf(sdl2::ttf::Font<'ttf, 'static>),
+    Bitmap,
+}
+
+/// Tilemap image, tilesets without one are drawn as coloured squares with tile indices.
+enum Tilemap<'a> {
+    Texture(Texture<'a>, u32),
+    Missing,
+}
+
 #[allow(unused_must_use)]
-fn draw_tile(canvas: &mut Canvas<Window>, tilemap: &Texture, col: u32, row: u32, x: u32, y:u32, angle: f64) {
+fn draw_tile(canvas: &mut Canvas<Window>, tilemap: &Texture, view: &View, col: u32, row: u32, dst: Rect, angle: f64) {
+    let size = view.tile_size;
     canvas.copy_ex(
         &tilemap,
-        Rect::new((col*TILESIZE) as i32, (row*TILESIZE) as i32, TILESIZE, TILESIZE),
-        Rect::new((x*TILESIZE_SCALED) as i32, (y*TILESIZE_SCALED) as i32, TILESIZE_SCALED, TILESIZE_SCALED),
+        Rect::new((col*size) as i32, (row*size) as i32, size, size),
+        dst,
         angle,
-        Point::new((TILESIZE_SCALED/2) as i32, (TILESIZE_SCALED/2) as i32),
+        Point::new((dst.width()/2) as i32, (dst.height()/2) as i32),
         false,
         false);
 }
 
+#[allow(unused_must_use)]
+fn draw_missing_tile<A>(canvas: &mut Canvas<Window>, font: &TextFont, texture_creator: &TextureCreator<A>, tile: &WfcTile, dst: Rect) {
+    let [r, g, b, _] = tile_color(tile);
+    canvas.set_draw_color(Color::RGB(r, g, b));
+    canvas.fill_rect(dst);
+    let margin = dst.width() / 4;
+    let rect = Rect::new(dst.x + margin as i32, dst.y + margin as i32, dst.width() - 2*margin, dst.height() - 2*margin);
+    draw_text_in_rect(canvas, font, texture_creator, rect, &tile.index.to_string(), Color::WHITE);
+}
 
 fn get_col_row(tile: &WfcTile, size: u32) -> (u32, u32) {
     let col = tile.index % size;
@@ -64,30 +185,67 @@ fn draw_outline_circle(canvas: &mut Canvas<Window>, x: u32, y: u32, r: i16, colo
     canvas.filled_circle(x as i16, y as i16, r-4, color);
 }
 
-fn draw_wfc_tile(canvas: &mut Canvas<Window>, tilemap: &Texture, wfc_tile: &WfcTile, size: u32, x: u32, y: u32) {
-    let (col, row) = get_col_row(wfc_tile, size);
-    draw_tile(canvas, &tilemap, col, row, x, y, wfc_tile.angle as f64);
+fn draw_any_tile<A>(canvas: &mut Canvas<Window>, tilemap: &Tilemap, view: &View, font: &TextFont, texture_creator: &TextureCreator<A>, tile: &WfcTile, dst: Rect) {
+    match tilemap {
+        Tilemap::Texture(texture, tilemap_size) => {
+            let (col, row) = get_col_row(tile, *tilemap_size);
+            draw_tile(canvas, texture, view, col, row, dst, tile.angle as f64);
+        },
+        Tilemap::Missing => draw_missing_tile(canvas, font, texture_creator, tile, dst),
+    }
+}
+
+fn draw_wfc_tile<A>(canvas: &mut Canvas<Window>, tilemap: &Tilemap, view: &View, font: &TextFont, texture_creator: &TextureCreator<A>, wfc_tile: &WfcTile, x: u32, y: u32) {
+    let dst = view.rect(x, y);
+    draw_any_tile(canvas, tilemap, view, font, texture_creator, wfc_tile, dst);
 
     if !SHOW_CONNECTIONS {
         return;
     }
 
     let con_types = wfc_tile.connection_types;
-    let x = x*TILESIZE_SCALED;
-    let y = y*TILESIZE_SCALED;
-    let half = TILESIZE_SCALED/2;
-    let fifth = TILESIZE_SCALED/7;
+    let (x, y) = (dst.x as u32, dst.y as u32);
+    let size = view.scaled;
+    let half = size/2;
+    let fifth = size/7;
     if con_types[0] < CON_TYPE_COLORS.len()
-        { draw_outline_circle(canvas, x + half,                    y + fifth,                   10, CON_TYPE_COLORS[con_types[0] % CON_TYPE_COLORS.len()]); }
+        { draw_outline_circle(canvas, x + half,         y + fifth,        10, CON_TYPE_COLORS[con_types[0] % CON_TYPE_COLORS.len()]); }
     if con_types[1] < CON_TYPE_COLORS.len()
-        { draw_outline_circle(canvas, x + TILESIZE_SCALED - fifth, y + half,                    10, CON_TYPE_COLORS[con_types[1] % CON_TYPE_COLORS.len()]); }
+        { draw_outline_circle(canvas, x + size - fifth, y + half,         10, CON_TYPE_COLORS[con_types[1] % CON_TYPE_COLORS.len()]); }
     if con_types[2] < CON_TYPE_COLORS.len()
-        { draw_outline_circle(canvas, x + half,                    y + TILESIZE_SCALED - fifth, 10, CON_TYPE_COLORS[con_types[2] % CON_TYPE_COLORS.len()]); }
+        { draw_outline_circle(canvas, x + half,         y + size - fifth, 10, CON_TYPE_COLORS[con_types[2] % CON_TYPE_COLORS.len()]); }
     if con_types[3] < CON_TYPE_COLORS.len()
-        { draw_outline_circle(canvas, x + fifth,                   y + half,                    10, CON_TYPE_COLORS[con_types[3] % CON_TYPE_COLORS.len()]); }
+        { draw_outline_circle(canvas, x + fifth,        y + half,         10, CON_TYPE_COLORS[con_types[3] % CON_TYPE_COLORS.len()]); }
 }
 
-fn draw_text_in_rect<A>(canvas: &mut Canvas<Window>, font: &sdl2::ttf::Font, texture_creator: &TextureCreator<A>, rect: Rect, text: &str, color: Color) {
+#[allow(unused_must_use)]
+fn draw_bitmap_text(canvas: &mut Canvas<Window>, rect: Rect, text: &str, color: Color) {
+    let digits: Vec<u16> = text.chars().filter_map(|x| x.to_digit(10)).map(|x| BITMAP_DIGITS[x as usize]).collect();
+    if digits.is_empty() {
+        return;
+    }
+    // digits are 3 pixels wide with 1 pixel between them
+    let columns = digits.len() as u32 * 4 - 1;
+    let pixel = std::cmp::min(rect.width() / columns, rect.height() / 5).max(1);
+    let x = rect.x + (rect.width() as i32 - (columns * pixel) as i32) / 2;
+    let y = rect.y + (rect.height() as i32 - (5 * pixel) as i32) / 2;
+    canvas.set_draw_color(color);
+    for (i, digit) in digits.iter().enumerate() {
+        for bit in 0..15 {
+            if digit & (1 << (14 - bit)) == 0 {
+                continue;
+            }
+            let (col, row) = (i as u32 * 4 + bit % 3, bit / 3);
+            canvas.fill_rect(Rect::new(x + (col*pixel) as i32, y + (row*pixel) as i32, pixel, pixel));
+        }
+    }
+}
+
+fn draw_text_in_rect<A>(canvas: &mut Canvas<Window>, font: &TextFont, texture_creator: &TextureCreator<A>, rect: Rect, text: &str, color: Color) {
+    let font = match font {
+        TextFont::Ttf(font) => font,
+        TextFont::Bitmap => return draw_bitmap_text(canvas, rect, text, color),
+    };
     let font_surface = font
         .render(text)
         .solid(color)
@@ -104,162 +262,135 @@ fn draw_text_in_rect<A>(canvas: &mut Canvas<Window>, font: &sdl2::ttf::Font, tex
     canvas.copy(&font_texture, None, r);
 }
 
-fn draw_stack_of_tiles<A>(canvas: &mut Canvas<Window>, tilemap: &Texture, font: &sdl2::ttf::Font, texture_creator: &TextureCreator<A>, stack: &Vec<WfcTile>, tilemap_size: u32, x: i32, y: i32) {
+fn draw_stack_of_tiles<A>(canvas: &mut Canvas<Window>, tilemap: &Tilemap, view: &View, font: &TextFont, texture_creator: &TextureCreator<A>, stack: &Vec<WfcTile>, x: u32, y: u32) {
     let stack_size: i32 = 6;
     let stack_len: usize = (stack_size*stack_size - 1) as usize;
+    let square = view.rect(x, y);
     for (i, tile) in (stack).iter().enumerate() {
-        let half_tilesize = TILESIZE_SCALED / stack_size as u32;
+        let half_tilesize = (view.scaled / stack_size as u32).max(1);
         let xx = i as i32 % stack_size;
         let yy = i as i32 / stack_size;
+        let dst = Rect::new(square.x + xx*half_tilesize as i32, square.y + yy*half_tilesize as i32, half_tilesize, half_tilesize);
 
         if i>=stack_len {
             draw_text_in_rect(
                 canvas,
-                &font,
+                font,
                 &texture_creator,
-                Rect::new(x + xx*half_tilesize as i32, y + yy*half_tilesize as i32, half_tilesize, half_tilesize),
+                dst,
                 &((stack.len() - stack_len).to_string()),
                 Color::WHITE);
             break;
         }
 
-        let (col, row) = get_col_row(tile, tilemap_size);
-        canvas.copy_ex(
-            tilemap,
-            Rect::new((col*TILESIZE) as i32, (row*TILESIZE) as i32, TILESIZE, TILESIZE),
-            Rect::new(x + xx*half_tilesize as i32, y + yy*half_tilesize as i32, half_tilesize, half_tilesize),
-            tile.angle as f64,
-            Point::new((half_tilesize/2) as i32, (half_tilesize/2) as i32),
-            false,
-            false);
+        draw_any_tile(canvas, tilemap, view, font, texture_creator, tile, dst);
     }
 }
 
-fn test_path(worldmap: Worldmap, seed: u64, size: (usize, usize)) -> (WFC, Vec<WfcTile>, String, u32) {
-    let (tilemap_path, tiles, tilemap_size) = flat_city_paths_only();
+fn test_path(worldmap: Worldmap, seed: u64) -> Pipeline {
+    let (_, tiles, _) = flat_city_paths_only();
     let mut ttiles = tiles.clone();
     ttiles.pop();
-    let mut wfc = WFC::init(worldmap, ttiles, seed);
-
-    wfc.add_tile([2,2,0], *tiles[3].clone().rotate(2)).unwrap();
-    wfc.add_tile([8,8,0], tiles[3]).unwrap();
-
-    let (x_size, y_size) = size;
-    for x in 0..x_size {
-        for y in 0..y_size {
-            if x == 0 || y == 0 || x == x_size-1 || y == y_size-1 {
-                wfc.add_tile([x,y,0], tiles[0]).unwrap();
-            }
-        }
-    }
-
-    return (wfc, tiles, tilemap_path, tilemap_size)
+    let mut pipeline = Pipeline::new(worldmap, seed);
+
+    // road between two dead ends
+    pipeline.add_pass("path", ttiles, flat_city_tags(), Box::new(move |wfc, _| {
+        wfc.add_tile([2,2,0], *tiles[3].clone().rotate(2))?;
+        wfc.add_tile([8,8,0], tiles[3])?;
+        wfc.surround_worldmap_2d(&tiles[0])
+    }));
+
+    // city built around that road
+    let (_, tiles, _) = flat_city();
+    pipeline.add_pass("city", tiles.clone(), flat_city_tags(), Box::new(move |wfc, previous| {
+        let previous = previous.ok_or_else(|| "error: no road to build city around".to_string())?;
+        let deadend_square = [2,2,0];
+        let roads = previous.tags.sockets_tagged("road");
+        let network = paths::trace_from(&previous.worldmap, &roads, deadend_square);
+        let route = network.dead_ends.iter()
+            .filter(|&&x| x != deadend_square)
+            .find_map(|&x| network.route(deadend_square, x))
+            .ok_or_else(|| format!("error: road from {:?} has no other end", deadend_square))?;
+
+        // TODO: move surround functions into worldmap
+        wfc.surround_worldmap_2d(&tiles[0])?;
+        let roads = wfc.tags.sockets_tagged("road");
+        let walls = wfc.tags.sockets_tagged("wall");
+        wfc.add_constraint(Constraint::PreservePath { route, roads, walls })
+    }));
+
+    return pipeline
 }
 
 pub fn main() {
     better_panic::install();
 
-    let mut seed = STARTING_SEED;
-    let (x_size, y_size, z_size) = MAP_SIZE;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|x| x == "--help" || x == "-h") {
+        print!("{}", USAGE);
+        return;
+    }
+    let config = match Config::from_args(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        },
+    };
+
+    let mut seed = config.seed;
+    let [x_size, y_size, z_size] = config.size;
     let worldmap = Worldmap::new3d(x_size, y_size, z_size);
     println!("worldmap: {} {:?}", worldmap.len, worldmap.size);
 
-    let (mut wfc, _, tilemap_path, tilemap_size) = test_path(worldmap, seed, (x_size, y_size));
-//    let (tilemap_path, mut tiles, tilemap_size) = pipes();
-    let (tilemap_path, tiles, tilemap_size) = flat_city();
-    //let (tilemap_path, mut tiles, tilemap_size) = stairs_3d();
-//    let (tilemap_path, mut tiles, tilemap_size, deadend) = stairs_3d_path();
-
-
-//    let mut wfc = WFC::init(worldmap, tiles.clone(), seed);
-
-//    // surround worldmap with empty tiles
-//    let empty_tile = tiles[0];
-//    wfc.surround_worldmap(&empty_tile);
-//    // add starting points
-//    wfc.add_tile([3,3,1], deadend).unwrap();
-//    wfc.add_tile([3,3,15], deadend).unwrap();
-
-    let initial_worldmap = wfc.worldmap.clone();
-    let mut stage = 0;
-
-
-/// Removes tiles from `square` stack which do not allow path from `dir` to `other_dir`
-fn preserve_path(wfc: &mut WFC, roads: &Vec<usize>, square: &[usize; 3], dir: Direction, other_dir: Direction) {
-    let dir: usize = dir.into();
-    let other_dir: usize = other_dir.into();
-
-    wfc.worldmap[*square].retain(|&x|
-        roads.contains(&x.connection_types[dir])
-        && roads.contains(&x.connection_types[other_dir]));
-    wfc.propagate(*square);
-}
-
-/// like preserve_path, but also preserve walls between immidieate neighbours.
-fn preserve_connections(wfc: &mut WFC, path_squares: &Vec<[usize; 3]>, roads: &Vec<usize>, walls: &Vec<usize>, square: &[usize; 3], dir: Direction, other_dir: Direction) {
-    let dir: usize = dir.into();
-    let other_dir: usize = other_dir.into();
+    let (mut pipeline, tilemap_path, tilemap_size) = match &config.tileset {
+        Some(tileset) => {
+            let setup = match &config.setup {
+                Some(path) => match std::fs::read_to_string(path) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        eprintln!("error: can't read {}: {}", path, e);
+                        std::process::exit(1);
+                    },
+                },
+                None => String::new(),
+            };
+            let mut pipeline = Pipeline::new(worldmap, seed);
+            pipeline.add_pass(&tileset.name, tileset.tiles.clone(), tileset.tags.clone(), Box::new(move |wfc, _| apply_setup(wfc, &setup)));
+            (pipeline, tileset.image.clone(), tileset.tilemap_size)
+        },
+        None => {
+            let (tilemap_path, _, tilemap_size) = flat_city();
+            (test_path(worldmap, seed), tilemap_path, tilemap_size)
+        },
+    };
+    let is_started = match pipeline.start_pass() {
+        Ok(()) => true,
+        Err(e) => {
+            println!("{}", e);
+            false
+        },
+    };
 
-    let keep_connections = &mut [false, false, false, false, false, false];
-    for i in 0..6 {
-        if let Some(sq) = wfc.worldmap.move_(*square, &Direction::from(i)) {
-            keep_connections[i] = path_squares.contains(&sq);
+    if AUTO_TRY {
+        let wfc = pipeline.current().unwrap();
+        let mut search = SeedSearch::new(seed..u64::MAX);
+        search.stop_on_success = STOP_ON_SUCCESS;
+        flag::register(signal_hook::consts::SIGINT, Arc::clone(&search.cancel)).unwrap();
+
+        let (found, stats) = search.run(wfc);
+        println!("tried {} seeds, {} succeeded, {} backtracks", stats.tried, stats.successes, stats.backtracks);
+        for (error, count) in &stats.errors {
+            println!("{:>6}x {}", count, error);
         }
-    }
-
-    wfc.worldmap[*square].retain(|&tile| {
-        // keep only tiles which:
-        // - `dir` & `other_dir` directions have `roads` connections
-        //   AND in keep_connections (excl. dir and other_dir) have
-        //   `walls` connections.
-        for i in 0..6 {
-            // road directions
-            if i == dir || i == other_dir {
-                if !roads.contains(&tile.connection_types[i]) {
-                    return false;
-                }
-            // walls direction
-            } else if keep_connections[i] {
-                if !walls.contains(&tile.connection_types[i]) {
-                    return false;
-                }
-            } else {
-                // any connections are ok
-            }
+        if stats.is_cancelled {
+            println!("\nReceived interrupt; Quiting...");
+            return;
         }
-        return true;
-    });
-    wfc.propagate(*square);
-}
-
-    if AUTO_TRY {
-        let interupt_flag = Arc::new(AtomicBool::new(false));
-        flag::register(signal_hook::consts::SIGINT, Arc::clone(&interupt_flag)).unwrap();
-
-        // this is important, because worldmap might have been initialised by add_tile()
-        let worldmap = wfc.worldmap.clone();
-        loop {
-            if interupt_flag.load(Ordering::Relaxed) {
-                println!("\nReceived interrupt; Quiting...");
-                return;
-            }
-
-            ::std::thread::sleep(Duration::from_millis(1));
-            println!("Trying seed: {})", wfc.seed);
-
-            let result = match wfc.run() {
-                Ok(_) => true,
-                Err(_) => false,
-            };
-
-            if STOP_ON_SUCCESS == result {
-                break;
-            }
-            seed += 1;
-            wfc.init_rng(seed);
-            wfc.worldmap = worldmap.clone();
+        if let Some((seed, wfc)) = found {
+            println!("seed: {}", seed);
+            wfc.print_worldmap();
         }
-        wfc.print_worldmap();
         return;
     }
 
@@ -267,8 +398,9 @@ fn preserve_connections(wfc: &mut WFC, path_squares: &Vec<[usize; 3]>, roads: &V
     let video_subsystem = sdl_context.video().unwrap();
     let ttf_context = sdl2::ttf::init().unwrap();
 
-    let window = video_subsystem.window("rust-sdl2 demo", WIN_WIDTH, WIN_HEIGHT)
+    let window = video_subsystem.window("rust-sdl2 demo", config.window.0, config.window.1)
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
 
@@ -281,12 +413,32 @@ fn preserve_connections(wfc: &mut WFC, path_squares: &Vec<[usize; 3]>, roads: &V
     let texture_creator: TextureCreator<sdl2::video::WindowContext> = canvas.texture_creator();
 
     // load font
-    let font = ttf_context.load_font("/home/terra/.local/share/fonts/Ubuntu-B.ttf", 128).unwrap();
-
-    let tilemap = texture_creator.load_texture(tilemap_path).unwrap();
-    let mut error_lock = false;
-    let mut main_path = Vec::<([usize;3], Direction, Direction)>::new();
-    let mut main_path_squares = Vec::<[usize;3]>::new();
+    let font = match &config.font {
+        Some(path) => match ttf_context.load_font(path, 128) {
+            Ok(font) => TextFont::Ttf(font),
+            Err(e) => {
+                println!("can't load font {}: {}, using bitmap font", path, e);
+                TextFont::Bitmap
+            },
+        },
+        None => TextFont::Bitmap,
+    };
+
+    let tilemap = match texture_creator.load_texture(&tilemap_path) {
+        Ok(texture) => Tilemap::Texture(texture, tilemap_size),
+        Err(e) => {
+            println!("can't load tilemap '{}': {}, drawing tile indices instead", tilemap_path, e);
+            Tilemap::Missing
+        },
+    };
+    let tile_size = match (&tilemap, config.tile_size) {
+        (_, Some(size)) => size,
+        (Tilemap::Texture(texture, size), None) => texture.query().width / size,
+        (Tilemap::Missing, None) => 8,
+    };
+    let mut error_lock = !is_started;
+    // pass in progress and its seed, reported whenever another one starts
+    let mut shown_pass = None;
 
     'running: loop {
         for event in event_pump.poll_iter() {
@@ -301,7 +453,7 @@ fn preserve_connections(wfc: &mut WFC, path_squares: &Vec<[usize; 3]>, roads: &V
                         continue;
                     }
 
-                    match wfc.wfc_step() {
+                    match pipeline.step() {
                         Err(e) => {
                               println!("{}", e);
                               error_lock = true;
@@ -310,24 +462,29 @@ fn preserve_connections(wfc: &mut WFC, path_squares: &Vec<[usize; 3]>, roads: &V
                     }
                 },
                 Event::KeyDown { keycode: Some(Keycode::N), .. } => {
-                    wfc.worldmap = initial_worldmap.clone();
                     seed += 1;
-                    wfc.init_rng(seed);
-                    stage = 0;
+                    pipeline.restart(seed);
+                    error_lock = false;
+                    if let Err(e) = pipeline.start_pass() {
+                        println!("{}", e);
+                        error_lock = true;
+                    }
                     println!("-- seed {} --", seed);
                 },
                 Event::KeyDown { keycode: Some(Keycode::R), .. } => {
-                    wfc.worldmap = initial_worldmap.clone();
-                    stage = 0;
-                    wfc.init_rng(seed);
+                    pipeline.restart(seed);
                     error_lock = false;
+                    if let Err(e) = pipeline.start_pass() {
+                        println!("{}", e);
+                        error_lock = true;
+                    }
                     println!("-- seed {} --", seed);
                 },
                 Event::KeyDown { keycode: Some(Keycode::Q), .. } => {
                     if error_lock {
                         println!("Locked in error state. Press [R] to restart current seed or [N] to try new seed.");
                     }
-                    match wfc.run() {
+                    match pipeline.run_pass() {
                         Err(e) => {
                             println!("{}", e);
                             error_lock = true;
@@ -335,93 +492,80 @@ fn preserve_connections(wfc: &mut WFC, path_squares: &Vec<[usize; 3]>, roads: &V
                         _ => (),
                     };
                 },
-                Event::KeyDown { keycode: Some(Keycode::O), .. } => {
-                    match stage {
-                        0 => {
-                            println!("stage 0 | generate path");
-                            wfc.run_until_success();
-                        },
-                        1 => {
-                            println!("stage 1 | find path");
-                            // replace all tiles not part of main path with empty
-                            let deadend_square = [2,2,0];
-                            let follow_conn = 0;
-                            let mut square = deadend_square;
-                            main_path.clear();
-                            main_path_squares.clear();
-                            main_path_squares.push(square);
-                            let mut last_dir = 99;
-                            'outer: loop {
-                                //println!("{:?}", wfc.worldmap[square][0].connection_types);
-                                for i in 0..4 {
-                                    let val = &wfc.worldmap[square][0].connection_types[i];
-                                    if val == &follow_conn && last_dir != i {
-                                        let dir = Direction::from(i);
-                                        //println!("follow {} {:?} {:?}", i, dir, square);
-                                        if last_dir != 99 {
-                                            main_path.push((square, dir.clone(), Direction::from(last_dir)));
-                                        }
-                                        main_path_squares.push(square);
-                                        square = wfc.worldmap.move_(square, &dir).unwrap();
-                                        last_dir = dir.flip().into();
-                                        //println!("new square {:?}", square);
-                                        continue 'outer;
-                                    }
-                                }
-                                break;
-                            }
-
-                            // visually show path
-                            for square in &wfc.squares_list {
-                                if main_path_squares.contains(square) {
-                                    continue;
-                                }
-                                wfc.worldmap[*square].clear();
-                            }
-                        },
-                        2 => {
-                            println!("stage 2 | recreate path as connections");
-                            wfc = WFC::init(Worldmap::new3d(x_size, y_size, z_size), tiles.clone(), seed);
-                            //wfc.worldmap = initial_worldmap.clone();
-                            // surround
-                            // TODO: move surround functions into worldmap
-                            wfc.surround_worldmap_2d(&tiles[0]).unwrap();
-
-                            // place deadends
-
-                            let roads = vec![0];
-                            let walls = vec![1];
-                            for (square, dir, other_dir) in &main_path {
-                                let _dir: usize = dir.clone().into();
-                                let _other_dir: usize = other_dir.clone().into();
-
-                                //preserve_path(&mut wfc, &roads, square, dir.clone(), other_dir.clone());
-                                preserve_connections(&mut wfc, &main_path_squares, &roads, &walls, &square, dir.clone(), other_dir.clone());
-                            }
+                Event::KeyDown { keycode: Some(Keycode::Z), .. } => {
+                    if !pipeline.undo() {
+                        println!("Nothing to undo.");
+                    }
+                    error_lock = false;
+                },
+                Event::KeyDown { keycode: Some(Keycode::X), .. } => {
+                    match pipeline.redo() {
+                        Ok(false) => println!("Nothing to redo."),
+                        Err(e) => println!("{}", e),
+                        _ => (),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::B), .. } => {
+                    match pipeline.branch() {
+                        Ok(false) => println!("Nothing to branch from."),
+                        Err(e) => {
+                            println!("{}", e);
+                            error_lock = true;
                         },
-                        3 => {
-                            println!("stage 3 | ");
-                            wfc.run_until_success();
+                        _ => (),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::S), .. } => {
+                    match pipeline.current().map(|wfc| wfc.save_to_file(SAVE_PATH)) {
+                        Some(Ok(())) => println!("saved to {}", SAVE_PATH),
+                        Some(Err(e)) => println!("{}", e),
+                        None => println!("Nothing to save."),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::L), .. } => {
+                    match WFC::load_from_file(SAVE_PATH) {
+                        Ok(wfc) => {
+                            pipeline.resume(wfc);
+                            error_lock = false;
+                            println!("loaded {}", SAVE_PATH);
                         },
-                        _ => {
-                            println!("stage {} unknown", stage);
-                        }
-                    };
-                    stage += 1;
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::O), .. } => {
+                    if let Err(e) = pipeline.run() {
+                        println!("{}", e);
+                        error_lock = true;
+                    }
                 },
                 _ => {}
             }
         }
 
+        let pass = pipeline.wfc.as_ref().map(|wfc| (pipeline.finished.len(), wfc.seed));
+        if let Some((index, pass_seed)) = pass.filter(|_| pass != shown_pass) {
+            println!("pass {} | {} | seed {}", index, pipeline.passes[index].name, pass_seed);
+            shown_pass = pass;
+        }
+
         // The rest of the game loop goes here...
         canvas.set_draw_color(Color::RGB(135, 135, 135));
         canvas.clear();
 
+        let wfc = match pipeline.current() {
+            Some(x) => x,
+            None => {
+                canvas.present();
+                ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+                continue;
+            },
+        };
+        let view = View::fit(tile_size, config.scale, canvas.output_size().unwrap(), (x_size, y_size));
         if SHOW_TILESET && wfc.worldmap[0].len() > 1 {
             for (i, tile) in wfc.worldmap[0].iter().enumerate() {
                 let x = i % x_size;
                 let y = i / x_size;
-                draw_wfc_tile(&mut canvas, &tilemap, tile, tilemap_size, x as u32, y as u32);
+                draw_wfc_tile(&mut canvas, &tilemap, &view, &font, &texture_creator, tile, x as u32, y as u32);
             }
         } else {
             // draw world map
@@ -429,17 +573,17 @@ fn preserve_connections(wfc: &mut WFC, path_squares: &Vec<[usize; 3]>, roads: &V
                 for y in 0..y_size {
                     let stack = &wfc.worldmap[(x,y)];
                     if stack.len() == 1 {
-                        draw_wfc_tile(&mut canvas, &tilemap, &(stack[0]), tilemap_size, x as u32, y as u32);
+                        draw_wfc_tile(&mut canvas, &tilemap, &view, &font, &texture_creator, &(stack[0]), x as u32, y as u32);
                     } else {
                         draw_stack_of_tiles(
                             &mut canvas,
                             &tilemap,
+                            &view,
                             &font,
                             &texture_creator,
                             &stack,
-                            tilemap_size,
-                            x as i32 * TILESIZE_SCALED as i32,
-                            y as i32 * TILESIZE_SCALED as i32);
+                            x as u32,
+                            y as u32);
                     }
                 }
             }
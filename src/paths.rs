@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+use crate::wfc::{WfcTile, Worldmap, Position, Direction};
+
+/// Sides of `square` leading to another active square.
+pub fn open_faces(worldmap: &Worldmap, square: Position) -> [bool; 6] {
+    let mut faces = [false; 6];
+    for (i, face) in faces.iter_mut().enumerate() {
+        *face = can_pass(worldmap, square, i);
+    }
+    faces
+}
+
+/// Tile has one of `sockets` on one of the open `faces`.
+pub fn tile_carries(tile: &WfcTile, faces: &[bool; 6], sockets: &[usize]) -> bool {
+    (0..6).any(|i| faces[i] && sockets.contains(&tile.connection_types[i]))
+}
+
+/// Some tile in the stack of `square` has one of `sockets` on side `dir`.
+pub fn may_connect(worldmap: &Worldmap, square: Position, dir: usize, sockets: &[usize]) -> bool {
+    worldmap[square].iter().any(|tile| sockets.contains(&tile.connection_types[dir]))
+}
+
+fn can_pass(worldmap: &Worldmap, square: Position, dir: usize) -> bool {
+    match worldmap.move_(square, &Direction::from(dir)) {
+        Some(next) => worldmap.is_active(next),
+        None => false,
+    }
+}
+
+/// Every tile in the stack of `square` has one of `sockets` on side `dir`.
+pub fn must_connect(worldmap: &Worldmap, square: Position, dir: usize, sockets: &[usize]) -> bool {
+    let stack = &worldmap[square];
+    !stack.is_empty() && stack.iter().all(|tile| sockets.contains(&tile.connection_types[dir]))
+}
+
+/// Marks squares reachable from `start` through faces which may still be connected by `sockets`.
+/// Result is indexed the same as worldmap cells.
+pub fn reachable(worldmap: &Worldmap, sockets: &[usize], start: Position) -> Vec<bool> {
+    distances(worldmap, sockets, start, false).iter().map(|x| x.is_some()).collect()
+}
+
+/// Number of steps from `start` to every square along `sockets`, indexed the same as worldmap cells.
+/// With `is_certain` only faces which are connected in every remaining tile combination are followed,
+/// otherwise faces which may still become connected are followed too.
+pub fn distances(worldmap: &Worldmap, sockets: &[usize], start: Position, is_certain: bool) -> Vec<Option<usize>> {
+    let connects = if is_certain { must_connect } else { may_connect };
+    let mut distance = vec![None; worldmap.len];
+    let mut queue = VecDeque::new();
+    distance[worldmap.index_of(start)] = Some(0);
+    queue.push_back(start);
+    while let Some(square) = queue.pop_front() {
+        let steps = distance[worldmap.index_of(square)].unwrap();
+        for dir in 0..6 {
+            if !can_pass(worldmap, square, dir) || !connects(worldmap, square, dir, sockets) {
+                continue;
+            }
+            let next = worldmap.move_(square, &Direction::from(dir)).unwrap();
+            let flipped: usize = Direction::from(dir).flip().into();
+            if distance[worldmap.index_of(next)].is_some() || !connects(worldmap, next, flipped, sockets) {
+                continue;
+            }
+            distance[worldmap.index_of(next)] = Some(steps + 1);
+            queue.push_back(next);
+        }
+    }
+    distance
+}
+
+/// `tile` at `square` has one of `sockets` on a side leading to a neighbour at most `max - 1` steps
+/// away by `distance` (see `distances`), so a route through it can be at most `max` steps long.
+pub fn leads_within(worldmap: &Worldmap, square: Position, tile: &WfcTile, sockets: &[usize], distance: &[Option<usize>], max: usize) -> bool {
+    (0..6).any(|dir| {
+        if !can_pass(worldmap, square, dir) || !sockets.contains(&tile.connection_types[dir]) {
+            return false;
+        }
+        let next = worldmap.move_(square, &Direction::from(dir)).unwrap();
+        distance[worldmap.index_of(next)].is_some_and(|x| x < max)
+    })
+}
+
+/// Side of `from` leading to `to`, if they are neighbours.
+pub fn direction_between(worldmap: &Worldmap, from: Position, to: Position) -> Option<Direction> {
+    (0..6).map(Direction::from).find(|dir| worldmap.move_(from, dir) == Some(to))
+}
+
+/// Connected component of a socket network traced on a solved worldmap.
+#[derive(Clone, Debug, Default)]
+pub struct Network {
+    /// squares in the order they were found
+    pub squares: Vec<Position>,
+    /// neighbours each square is connected with
+    pub links: HashMap<Position, Vec<Position>>,
+    /// squares connected to exactly one other square
+    pub dead_ends: Vec<Position>,
+    /// squares connected to three or more other squares
+    pub branches: Vec<Position>,
+    /// chains of squares between dead ends and branches (both included); a loop without
+    /// any branch is a single chain which starts and ends in the same square
+    pub paths: Vec<Vec<Position>>,
+    /// number of independent loops
+    pub loops: usize,
+}
+
+impl Network {
+    pub fn degree(&self, square: Position) -> usize {
+        self.links.get(&square).map_or(0, |x| x.len())
+    }
+
+    /// Shortest chain of squares from `from` to `to` (both included) along the network.
+    pub fn route(&self, from: Position, to: Position) -> Option<Vec<Position>> {
+        let mut previous = HashMap::new();
+        let mut queue = VecDeque::new();
+        previous.insert(from, from);
+        queue.push_back(from);
+        while let Some(square) = queue.pop_front() {
+            if square == to {
+                let mut route = vec![to];
+                let mut square = to;
+                while square != from {
+                    square = previous[&square];
+                    route.push(square);
+                }
+                route.reverse();
+                return Some(route);
+            }
+            for &next in self.links.get(&square)? {
+                if let Entry::Vacant(entry) = previous.entry(next) {
+                    entry.insert(square);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    fn find_paths(&mut self) {
+        let is_junction = |network: &Network, square: Position| network.degree(square) != 2;
+        let mut used = HashSet::new();
+        let walk = |network: &Network, start: Position, first: Position, used: &mut HashSet<(Position, Position)>| {
+            let mut path = vec![start, first];
+            used.insert((start, first));
+            used.insert((first, start));
+            let (mut prev, mut square) = (start, first);
+            while !is_junction(network, square) && square != start {
+                let next = *network.links[&square].iter().find(|&&x| x != prev).unwrap_or(&prev);
+                used.insert((square, next));
+                used.insert((next, square));
+                path.push(next);
+                prev = square;
+                square = next;
+            }
+            path
+        };
+
+        let mut paths = Vec::new();
+        for &square in self.squares.iter().filter(|&&x| is_junction(self, x)) {
+            for &next in &self.links[&square] {
+                if !used.contains(&(square, next)) {
+                    paths.push(walk(self, square, next, &mut used));
+                }
+            }
+        }
+        // what is left are loops without any junction
+        for &square in &self.squares {
+            for &next in &self.links[&square] {
+                if !used.contains(&(square, next)) {
+                    paths.push(walk(self, square, next, &mut used));
+                }
+            }
+        }
+        self.paths = paths;
+    }
+}
+
+/// Traces network of `sockets` on a solved worldmap starting from `start`.
+pub fn trace_from(worldmap: &Worldmap, sockets: &[usize], start: Position) -> Network {
+    let mut network = Network::default();
+    let mut queue = VecDeque::new();
+    network.links.insert(start, Vec::new());
+    network.squares.push(start);
+    queue.push_back(start);
+    let mut edges: usize = 0;
+    while let Some(square) = queue.pop_front() {
+        for dir in 0..6 {
+            if !can_pass(worldmap, square, dir) || !must_connect(worldmap, square, dir, sockets) {
+                continue;
+            }
+            let next = worldmap.move_(square, &Direction::from(dir)).unwrap();
+            let flipped: usize = Direction::from(dir).flip().into();
+            if !must_connect(worldmap, next, flipped, sockets) {
+                continue;
+            }
+            if next == square || network.links[&square].contains(&next) {
+                continue;
+            }
+            network.links.get_mut(&square).unwrap().push(next);
+            if let Entry::Vacant(entry) = network.links.entry(next) {
+                entry.insert(Vec::new());
+                network.squares.push(next);
+                queue.push_back(next);
+            }
+            // every link is seen from both sides
+            edges += 1;
+        }
+    }
+
+    let squares = network.squares.clone();
+    network.dead_ends = squares.iter().filter(|&&x| network.degree(x) == 1).cloned().collect();
+    network.branches = squares.iter().filter(|&&x| network.degree(x) >= 3).cloned().collect();
+    network.loops = (edges / 2 + 1).saturating_sub(squares.len());
+    network.find_paths();
+    network
+}
+
+/// Traces all separate networks of `sockets` on a solved worldmap.
+pub fn trace(worldmap: &Worldmap, sockets: &[usize]) -> Vec<Network> {
+    let [x_size, y_size, z_size] = worldmap.size;
+    let mut visited = HashSet::new();
+    let mut networks = Vec::new();
+    for z in 0..z_size {
+    for y in 0..y_size {
+    for x in 0..x_size {
+        let square = [x, y, z];
+        if visited.contains(&square) || worldmap[square].len() != 1 {
+            continue;
+        }
+        let faces = open_faces(worldmap, square);
+        if !tile_carries(&worldmap[square][0], &faces, sockets) {
+            continue;
+        }
+        let network = trace_from(worldmap, sockets, square);
+        visited.extend(network.squares.iter().cloned());
+        networks.push(network);
+    }
+    }
+    }
+    networks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traces_networks() {
+        // loop with a dead end sticking out of its junction, and a separate straight piece
+        //   ┌─┐.
+        //   └─┴╴
+        //   ╶╴..
+        let roads: [[usize; 4]; 12] = [
+            [0, 1, 1, 0], [0, 1, 0, 1], [0, 0, 1, 1], [0, 0, 0, 0],
+            [1, 1, 0, 0], [0, 1, 0, 1], [1, 1, 0, 1], [0, 0, 0, 1],
+            [0, 1, 0, 0], [0, 0, 0, 1], [0, 0, 0, 0], [0, 0, 0, 0],
+        ];
+        let mut worldmap = Worldmap::new3d(4, 3, 1);
+        for (i, sides) in roads.iter().enumerate() {
+            let connection_types = [sides[0], sides[1], sides[2], sides[3], 0, 0];
+            worldmap[i] = vec![WfcTile { index: 0, connection_types, angle: 0, is_rotatable: false }];
+        }
+
+        let networks = trace(&worldmap, &[1]);
+        assert_eq!(networks.len(), 2);
+        let (ring, piece) = (&networks[0], &networks[1]);
+        assert_eq!(ring.squares.len(), 7);
+        assert_eq!(ring.loops, 1);
+        assert_eq!(ring.dead_ends, [[3, 1, 0]]);
+        assert_eq!(ring.branches, [[2, 1, 0]]);
+        let mut lengths: Vec<usize> = ring.paths.iter().map(|x| x.len()).collect();
+        lengths.sort();
+        assert_eq!(lengths, [2, 7]);
+        assert_eq!(ring.route([0, 0, 0], [3, 1, 0]).unwrap().len(), 5);
+
+        assert_eq!(piece.squares.len(), 2);
+        assert_eq!(piece.loops, 0);
+        assert_eq!(piece.dead_ends.len(), 2);
+        assert_eq!(piece.paths, [vec![[0, 2, 0], [1, 2, 0]]]);
+        assert!(ring.route([0, 0, 0], [0, 2, 0]).is_none());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::wfc::{WfcTile, Worldmap, WFC};
+use crate::constraints::Tags;
+
+/// Prepares solver of a pass, gets solver of the previous pass (`None` for the first one).
+pub type PassSetup = Box<dyn Fn(&mut WFC, Option<&WFC>) -> Result<(), String>>;
+
+/// Single solver run of a `Pipeline`, with its own tileset.
+pub struct Pass {
+    pub name: String,
+    pub tiles: Vec<WfcTile>,
+    pub tags: Tags,
+    pub setup: PassSetup,
+}
+
+/// Chain of solver passes where every pass is constrained by the result of the previous one,
+/// e.g. coarse road layout followed by detailed city built around those roads.
+///
+/// Can be run headless with `run` or stepped one collapse at a time with `step`.
+pub struct Pipeline {
+    pub passes: Vec<Pass>,
+    /// shape of the worldmap every pass starts with
+    pub worldmap: Worldmap,
+    pub seed: u64,
+    /// how many seeds a pass may try before the pipeline fails
+    pub attempts: u64,
+    /// solvers of finished passes
+    pub finished: Vec<WFC>,
+    /// solver of the pass in progress, created by `start_pass`
+    pub wfc: Option<WFC>,
+    tries: u64,
+}
+
+impl Pipeline {
+    pub fn new(worldmap: Worldmap, seed: u64) -> Pipeline {
+        Pipeline {
+            passes: Vec::new(),
+            worldmap,
+            seed,
+            attempts: 100,
+            finished: Vec::new(),
+            wfc: None,
+            tries: 0,
+        }
+    }
+
+    pub fn add_pass(&mut self, name: &str, tiles: Vec<WfcTile>, tags: Tags, setup: PassSetup) -> &mut Self {
+        self.passes.push(Pass { name: name.to_string(), tiles, tags, setup });
+        self
+    }
+
+    /// Drops all results and starts again from the first pass with `seed`.
+    pub fn restart(&mut self, seed: u64) {
+        self.seed = seed;
+        self.finished.clear();
+        self.wfc = None;
+        self.tries = 0;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.finished.len() == self.passes.len()
+    }
+
+    /// Solver of the pass in progress, or of the last finished one.
+    pub fn current(&self) -> Option<&WFC> {
+        self.wfc.as_ref().or_else(|| self.finished.last())
+    }
+
+    /// Continues with `wfc` (e.g. loaded by `WFC::load`) as the solver of the pass in progress,
+    /// or of the last finished pass if there is none in progress.
+    pub fn resume(&mut self, wfc: WFC) {
+        if self.wfc.is_none() {
+            self.finished.pop();
+        }
+        self.wfc = Some(wfc);
+    }
+
+    /// Creates and sets up solver for the next pass, unless there is one already.
+    pub fn start_pass(&mut self) -> Result<(), String> {
+        if self.wfc.is_some() || self.is_done() {
+            return Ok(());
+        }
+        let pass = &self.passes[self.finished.len()];
+        let mut wfc = WFC::init(self.worldmap.clone(), pass.tiles.clone(), self.seed + self.tries);
+        wfc.tags = pass.tags.clone();
+        (pass.setup)(&mut wfc, self.finished.last())
+            .map_err(|e| format!("error: setup of pass '{}' failed: {}", pass.name, e))?;
+        self.wfc = Some(wfc);
+        Ok(())
+    }
+
+    /// Makes one solver step, starting and finishing passes as needed.
+    /// Failed pass is restarted with the next seed until `attempts` run out.
+    /// Returns true when all passes are done.
+    pub fn step(&mut self) -> Result<bool, String> {
+        if self.is_done() {
+            return Ok(true);
+        }
+        if self.wfc.is_none() {
+            self.start_pass()?;
+            return Ok(false);
+        }
+
+        let wfc = self.wfc.as_mut().unwrap();
+        match wfc.wfc_step() {
+            Ok(false) => Ok(false),
+            Ok(true) => {
+                self.finished.push(self.wfc.take().unwrap());
+                self.tries = 0;
+                Ok(self.is_done())
+            },
+            Err(e) => {
+                self.wfc = None;
+                self.tries += 1;
+                if self.tries >= self.attempts {
+                    return Err(format!("error: pass '{}' failed after {} attempts, last error: {}", self.passes[self.finished.len()].name, self.tries, e));
+                }
+                Ok(false)
+            },
+        }
+    }
+
+    /// Takes back the last step, going back into the last finished pass if needed.
+    /// Returns false if there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        if self.wfc.is_none() {
+            match self.finished.pop() {
+                Some(wfc) => self.wfc = Some(wfc),
+                None => return false,
+            }
+        }
+        self.wfc.as_mut().unwrap().undo_step()
+    }
+
+    /// Makes the last step taken back by `undo` again.
+    pub fn redo(&mut self) -> Result<bool, String> {
+        match self.wfc.as_mut() {
+            Some(wfc) => wfc.redo_step(),
+            None => Ok(false),
+        }
+    }
+
+    /// Takes back the last step and makes it again with another tile, see `WFC::branch_step`.
+    pub fn branch(&mut self) -> Result<bool, String> {
+        if self.wfc.is_none() {
+            match self.finished.pop() {
+                Some(wfc) => self.wfc = Some(wfc),
+                None => return Ok(false),
+            }
+        }
+        self.wfc.as_mut().unwrap().branch_step()
+    }
+
+    /// Runs until the pass in progress (or the next one) is finished.
+    pub fn run_pass(&mut self) -> Result<&WFC, String> {
+        let pass = self.finished.len();
+        while self.finished.len() == pass && !self.is_done() {
+            self.step()?;
+        }
+        self.finished.last().ok_or_else(|| "error: pipeline has no passes".to_string())
+    }
+
+    /// Runs all remaining passes, returns solver of the last one.
+    pub fn run(&mut self) -> Result<&WFC, String> {
+        while !self.step()? {}
+        self.finished.last().ok_or_else(|| "error: pipeline has no passes".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraints::{Region, TileFilter};
+    use crate::tilesets::pipes;
+
+    #[test]
+    fn runs_passes() {
+        let (_, tiles, _) = pipes();
+        let empty = tiles[1];
+        let mut pipeline = Pipeline::new(Worldmap::new3d(6, 6, 1), 3);
+        pipeline.add_pass("layout", tiles.clone(), Tags::default(), Box::new(|_, _| Ok(())));
+        // second pass keeps the empty squares of the first one
+        pipeline.add_pass("detail", tiles.clone(), Tags::default(), Box::new(move |wfc, previous| {
+            let previous = previous.unwrap();
+            let region = Region::from_fn(&wfc.worldmap, |x| previous.worldmap[x][0] == empty);
+            wfc.restrict(region, TileFilter::Indices(vec![empty.index]))
+        }));
+
+        let detail = pipeline.run().unwrap().worldmap.clone();
+        assert!(pipeline.is_done());
+        let layout = &pipeline.finished[0].worldmap;
+        for i in 0..layout.len {
+            assert_eq!(detail[i].len(), 1);
+            if layout[i][0] == empty {
+                assert_eq!(detail[i][0].index, empty.index);
+            }
+        }
+
+        // stepping gives the same result as running
+        pipeline.restart(3);
+        while !pipeline.step().unwrap() {}
+        assert_eq!(pipeline.finished[1].worldmap.values, detail.values);
+    }
+}
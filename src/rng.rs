@@ -0,0 +1,77 @@
+/// Small portable PRNG (SplitMix64). Unlike `rand::rngs::StdRng` its output is fixed forever,
+/// so the same seed gives the same worldmap on every platform and with every dependency version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rng {
+    state: u64,
+}
+
+/// Scrambles `x` into a well distributed value (SplitMix64 finalizer).
+pub fn mix(x: u64) -> u64 {
+    let mut x = x;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Combines `seed` and `keys` into a new seed, used to derive independent streams.
+pub fn hash(seed: u64, keys: &[u64]) -> u64 {
+    keys.iter().fold(mix(seed), |x, &key| mix(x ^ key.wrapping_add(0x9e3779b97f4a7c15)))
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    /// Independent generator for `keys` (e.g. a square position) under `seed`.
+    pub fn stream(seed: u64, keys: &[u64]) -> Rng {
+        Rng::new(hash(seed, keys))
+    }
+
+    /// Current state, `Rng::new(state)` continues the same sequence.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        mix(self.state)
+    }
+
+    /// Uniformly distributed number in `0..n`, `n` must not be 0.
+    pub fn below(&mut self, n: usize) -> usize {
+        // Lemire's multiply and shift, rejecting the biased low part
+        let n = n as u64;
+        let threshold = n.wrapping_neg() % n;
+        loop {
+            let x = (self.next_u64() as u128) * (n as u128);
+            if x as u64 >= threshold {
+                return (x >> 64) as usize;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // these values must never change, worldmaps of existing seeds depend on them
+    #[test]
+    fn golden_sequence() {
+        let mut rng = Rng::new(0);
+        let values: Vec<u64> = (0..3).map(|_| rng.next_u64()).collect();
+        assert_eq!(values, [0xe220a8397b1dcdaf, 0x6e789e6aa1b965f4, 0x06c45d188009454f]);
+
+        let mut rng = Rng::new(204);
+        let values: Vec<usize> = (0..8).map(|_| rng.below(10)).collect();
+        assert_eq!(values, [0, 9, 0, 7, 3, 1, 6, 4]);
+    }
+
+    #[test]
+    fn streams_are_independent() {
+        assert_ne!(Rng::stream(1, &[0, 0, 0]), Rng::stream(1, &[1, 0, 0]));
+        assert_ne!(Rng::stream(1, &[0, 0, 0]), Rng::stream(2, &[0, 0, 0]));
+        assert_eq!(Rng::stream(1, &[4, 2, 0]), Rng::stream(1, &[4, 2, 0]));
+    }
+}
@@ -0,0 +1,608 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use crate::wfc::{WfcTile, Worldmap, Position, Boundary, Step, RngStreams, Propagation, WFC};
+use crate::constraints::{Constraint, Region, TileFilter, Tags};
+use crate::rng::Rng;
+
+/// Version written into save files, bumped whenever the format changes.
+pub const SAVE_VERSION: u32 = 1;
+
+/*
+Save files are plain text made of whitespace separated tokens, `#` starts a comment until the
+end of the line. A file starts with `wfc-state <version>`, followed by sections introduced by
+a keyword, see `WFC::save`.
+
+- tile:      index/angle/r|f/c0,c1,c2,c3,c4,c5   (r - rotatable, f - fixed)
+- position:  x,y,z
+- list:      1,2,3  or  -  for an empty list
+- flags:     0110   or  -  for no flags
+- string:    token with `%`, whitespace and `#` escaped as %XX
+*/
+
+/// Reads tokens of a save or constraint file one at a time.
+pub struct Tokens<'a> {
+    tokens: Vec<&'a str>,
+    next: usize,
+}
+
+impl<'a> Tokens<'a> {
+    pub fn new(text: &'a str) -> Tokens<'a> {
+        let tokens = text.lines()
+            .map(|line| line.split('#').next().unwrap())
+            .flat_map(|line| line.split_whitespace())
+            .collect();
+        Tokens { tokens, next: 0 }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next >= self.tokens.len()
+    }
+
+    pub fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).cloned()
+    }
+
+    pub fn next_token(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or_else(|| "error: unexpected end of file".to_string())?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    pub fn expect(&mut self, keyword: &str) -> Result<(), String> {
+        let token = self.next_token()?;
+        if token != keyword {
+            return Err(format!("error: expected '{}', found '{}'", keyword, token));
+        }
+        Ok(())
+    }
+
+    pub fn parse<T: FromStr>(&mut self) -> Result<T, String> {
+        let token = self.next_token()?;
+        token.parse().map_err(|_| format!("error: invalid number '{}'", token))
+    }
+
+    pub fn list(&mut self) -> Result<Vec<usize>, String> {
+        parse_list(self.next_token()?)
+    }
+
+    pub fn position(&mut self) -> Result<Position, String> {
+        let token = self.next_token()?;
+        let values = parse_list(token)?;
+        if values.len() != 3 {
+            return Err(format!("error: invalid position '{}'", token));
+        }
+        Ok([values[0], values[1], values[2]])
+    }
+
+    pub fn positions(&mut self) -> Result<Vec<Position>, String> {
+        let count: usize = self.parse()?;
+        (0..count).map(|_| self.position()).collect()
+    }
+
+    /// Flags written by `write_flags`.
+    pub fn flags(&mut self) -> Result<Vec<bool>, String> {
+        match self.next_token()? {
+            "-" => Ok(Vec::new()),
+            token => Ok(token.chars().map(|x| x == '1').collect()),
+        }
+    }
+
+    pub fn tile(&mut self) -> Result<WfcTile, String> {
+        let token = self.next_token()?;
+        let invalid = || format!("error: invalid tile '{}'", token);
+        let parts: Vec<&str> = token.split('/').collect();
+        if parts.len() != 4 {
+            return Err(invalid());
+        }
+        let connections = parse_list(parts[3])?;
+        if connections.len() != 6 || (parts[2] != "r" && parts[2] != "f") {
+            return Err(invalid());
+        }
+        let mut connection_types = [0; 6];
+        connection_types.copy_from_slice(&connections);
+        Ok(WfcTile {
+            index: parts[0].parse().map_err(|_| invalid())?,
+            angle: parts[1].parse().map_err(|_| invalid())?,
+            is_rotatable: parts[2] == "r",
+            connection_types,
+        })
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        unescape(self.next_token()?)
+    }
+
+    pub fn strings(&mut self) -> Result<Vec<String>, String> {
+        let count: usize = self.parse()?;
+        (0..count).map(|_| self.string()).collect()
+    }
+}
+
+fn parse_list(token: &str) -> Result<Vec<usize>, String> {
+    if token == "-" {
+        return Ok(Vec::new());
+    }
+    token.split(',')
+        .map(|x| x.parse().map_err(|_| format!("error: invalid list '{}'", token)))
+        .collect()
+}
+
+pub fn write_list(values: &[usize]) -> String {
+    if values.is_empty() {
+        return "-".to_string();
+    }
+    values.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(",")
+}
+
+/// Flags as a string of `0` and `1`, `-` if there are none.
+pub fn write_flags(flags: &[bool]) -> String {
+    if flags.is_empty() {
+        return "-".to_string();
+    }
+    flags.iter().map(|&x| if x { '1' } else { '0' }).collect()
+}
+
+pub fn write_position(square: Position) -> String {
+    write_list(&square)
+}
+
+pub fn write_positions(squares: &[Position]) -> String {
+    let mut rv = squares.len().to_string();
+    for &square in squares {
+        rv += " ";
+        rv += &write_position(square);
+    }
+    rv
+}
+
+pub fn write_tile(tile: &WfcTile) -> String {
+    format!("{}/{}/{}/{}", tile.index, tile.angle, if tile.is_rotatable { "r" } else { "f" }, write_list(&tile.connection_types))
+}
+
+fn escape(text: &str) -> String {
+    let mut rv = String::new();
+    for c in text.chars() {
+        if c == '%' || c == '#' || c.is_whitespace() {
+            rv += &format!("%{:02X}", c as u32);
+        } else {
+            rv.push(c);
+        }
+    }
+    if rv.is_empty() { "%".to_string() } else { rv }
+}
+
+fn unescape(token: &str) -> Result<String, String> {
+    if token == "%" {
+        return Ok(String::new());
+    }
+    let mut rv = String::new();
+    let mut chars = token.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            rv.push(c);
+            continue;
+        }
+        let code: String = chars.by_ref().take(2).collect();
+        let c = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32)
+            .ok_or_else(|| format!("error: invalid string '{}'", token))?;
+        rv.push(c);
+    }
+    Ok(rv)
+}
+
+fn write_strings(strings: &[String]) -> String {
+    let mut rv = strings.len().to_string();
+    for x in strings {
+        rv += " ";
+        rv += &escape(x);
+    }
+    rv
+}
+
+pub fn write_filter(filter: &TileFilter) -> String {
+    match filter {
+        TileFilter::Indices(indices) => format!("indices {}", write_list(&indices.iter().map(|&x| x as usize).collect::<Vec<usize>>())),
+        TileFilter::Tags(tags) => format!("tags {}", write_strings(tags)),
+        TileFilter::SocketTags(tags) => format!("socket_tags {}", write_strings(tags)),
+        TileFilter::Not(filter) => format!("not {}", write_filter(filter)),
+    }
+}
+
+pub fn read_filter(tokens: &mut Tokens) -> Result<TileFilter, String> {
+    match tokens.next_token()? {
+        "indices" => Ok(TileFilter::Indices(tokens.list()?.iter().map(|&x| x as u32).collect())),
+        "tags" => Ok(TileFilter::Tags(tokens.strings()?)),
+        "socket_tags" => Ok(TileFilter::SocketTags(tokens.strings()?)),
+        "not" => Ok(TileFilter::negate(read_filter(tokens)?)),
+        x => Err(format!("error: unknown tile filter '{}'", x)),
+    }
+}
+
+pub fn write_region(region: &Region) -> String {
+    match region {
+        Region::Box(from, to) => format!("box {} {}", write_position(*from), write_position(*to)),
+        Region::Mask(mask) => format!("mask {}", write_flags(mask)),
+    }
+}
+
+pub fn read_region(tokens: &mut Tokens) -> Result<Region, String> {
+    match tokens.next_token()? {
+        "box" => Ok(Region::Box(tokens.position()?, tokens.position()?)),
+        "mask" => Ok(Region::Mask(tokens.flags()?)),
+        x => Err(format!("error: unknown region '{}'", x)),
+    }
+}
+
+pub fn write_constraint(constraint: &Constraint) -> String {
+    match constraint {
+        Constraint::Restrict(region, filter) =>
+            format!("restrict {} {}", write_region(region), write_filter(filter)),
+        Constraint::Count { filter, min, max } =>
+            format!("count {} {} {}", min, max, write_filter(filter)),
+        Constraint::Connected { sockets, endpoints } =>
+            format!("connected {} {}", write_list(sockets), write_positions(endpoints)),
+        Constraint::Path { sockets, endpoints, min, max } =>
+            format!("path {} {} {} {}", write_list(sockets), min, max, write_positions(endpoints)),
+        Constraint::PreservePath { route, roads, walls } =>
+            format!("preserve_path {} {} {}", write_list(roads), write_list(walls), write_positions(route)),
+    }
+}
+
+pub fn read_constraint(tokens: &mut Tokens) -> Result<Constraint, String> {
+    match tokens.next_token()? {
+        "restrict" => Ok(Constraint::Restrict(read_region(tokens)?, read_filter(tokens)?)),
+        "count" => {
+            let (min, max) = (tokens.parse()?, tokens.parse()?);
+            Ok(Constraint::Count { filter: read_filter(tokens)?, min, max })
+        },
+        "connected" => Ok(Constraint::Connected { sockets: tokens.list()?, endpoints: tokens.positions()? }),
+        "path" => {
+            let sockets = tokens.list()?;
+            let (min, max) = (tokens.parse()?, tokens.parse()?);
+            Ok(Constraint::Path { sockets, min, max, endpoints: tokens.positions()? })
+        },
+        "preserve_path" => Ok(Constraint::PreservePath { roads: tokens.list()?, walls: tokens.list()?, route: tokens.positions()? }),
+        x => Err(format!("error: unknown constraint '{}'", x)),
+    }
+}
+
+fn write_boundary(boundary: &Boundary) -> String {
+    match boundary {
+        Boundary::Free => "free".to_string(),
+        Boundary::Fixed(sockets) => format!("fixed {}", write_list(sockets)),
+        Boundary::Periodic => "periodic".to_string(),
+        Boundary::Mirror => "mirror".to_string(),
+    }
+}
+
+pub fn read_boundary(tokens: &mut Tokens) -> Result<Boundary, String> {
+    match tokens.next_token()? {
+        "free" => Ok(Boundary::Free),
+        "fixed" => Ok(Boundary::Fixed(tokens.list()?)),
+        "periodic" => Ok(Boundary::Periodic),
+        "mirror" => Ok(Boundary::Mirror),
+        x => Err(format!("error: unknown boundary '{}'", x)),
+    }
+}
+
+fn write_tags(tags: &Tags) -> String {
+    let mut rv = String::new();
+    let mut tiles: Vec<&u32> = tags.tiles.keys().collect();
+    tiles.sort();
+    for index in tiles {
+        rv += &format!("tile_tags {} {}\n", index, write_strings(&tags.tiles[index]));
+    }
+    let mut sockets: Vec<&usize> = tags.sockets.keys().collect();
+    sockets.sort();
+    for socket in sockets {
+        rv += &format!("socket_tags {} {}\n", socket, write_strings(&tags.sockets[socket]));
+    }
+    rv
+}
+
+/// Tile variants used in stacks, saved once and referenced by their number.
+struct Variants {
+    tiles: Vec<WfcTile>,
+    numbers: HashMap<String, usize>,
+}
+
+impl Variants {
+    fn number(&mut self, tile: &WfcTile) -> usize {
+        let key = write_tile(tile);
+        if let Some(&x) = self.numbers.get(&key) {
+            return x;
+        }
+        self.tiles.push(*tile);
+        self.numbers.insert(key, self.tiles.len() - 1);
+        self.tiles.len() - 1
+    }
+
+    fn stack(&mut self, stack: &[WfcTile]) -> String {
+        write_list(&stack.iter().map(|tile| self.number(tile)).collect::<Vec<usize>>())
+    }
+}
+
+fn read_stack(tokens: &mut Tokens, variants: &[WfcTile]) -> Result<Vec<WfcTile>, String> {
+    tokens.list()?.iter()
+        .map(|&x| variants.get(x).cloned().ok_or_else(|| format!("error: unknown tile variant {}", x)))
+        .collect()
+}
+
+fn read_steps(tokens: &mut Tokens, variants: &[WfcTile]) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    for _ in 0..tokens.parse::<usize>()? {
+        let square = tokens.position()?;
+        let tile = *variants.get(tokens.parse::<usize>()?).ok_or_else(|| "error: unknown tile variant".to_string())?;
+        let (squares_index, trail_len) = (tokens.parse()?, tokens.parse()?);
+        let is_manual = match tokens.next_token()? {
+            "manual" => true,
+            "solver" => false,
+            x => return Err(format!("error: unknown step kind '{}'", x)),
+        };
+        steps.push(Step { square, tile, squares_index, trail_len, is_manual });
+    }
+    Ok(steps)
+}
+
+impl WFC {
+    /// Full solver state as text, `load` continues exactly where the solver stopped.
+    /// Explanation log (`explain`, `reductions`) is not saved.
+    pub fn save(&self) -> String {
+        let mut variants = Variants { tiles: Vec::new(), numbers: HashMap::new() };
+        let mut body = String::new();
+
+        let worldmap = &self.worldmap;
+        body += &format!("size {}\n", write_position(worldmap.size));
+        body += &format!("active {}\n", write_flags(&worldmap.active));
+        body += &format!("mask_sockets {}\n", match &worldmap.mask_sockets {
+            Some(sockets) => format!("some {}", write_list(sockets)),
+            None => "none".to_string(),
+        });
+        body += "boundaries";
+        for boundary in &worldmap.boundaries {
+            body += " ";
+            body += &write_boundary(boundary);
+        }
+        body += "\nstacks\n";
+        for stack in &worldmap.values {
+            body += &variants.stack(stack);
+            body += "\n";
+        }
+
+        body += &format!("squares {}\n", write_positions(&self.squares_list));
+        body += &format!("squares_index {}\n", self.squares_index);
+        body += &format!("constraints {}\n", self.constraints.len());
+        for constraint in &self.constraints {
+            body += &write_constraint(constraint);
+            body += "\n";
+        }
+        body += &format!("trail {}\n", self.trail.len());
+        for (square, stack) in &self.trail {
+            body += &format!("{} {}\n", write_position(*square), variants.stack(stack));
+        }
+        for (keyword, steps) in [("steps", &self.steps), ("undone", &self.undone)] {
+            body += &format!("{} {}\n", keyword, steps.len());
+            for step in steps {
+                let kind = if step.is_manual { "manual" } else { "solver" };
+                body += &format!("{} {} {} {} {}\n", write_position(step.square), variants.number(&step.tile), step.squares_index, step.trail_len, kind);
+            }
+        }
+
+        let mut text = format!("wfc-state {}\n", SAVE_VERSION);
+        text += &format!("seed {}\n", self.seed);
+        text += &format!("rng {}\n", self.rng.state());
+        text += &match self.rng_streams {
+            RngStreams::Single => "rng_streams single\n".to_string(),
+            RngStreams::PerRegion(block) => format!("rng_streams per_region {}\n", write_position(block)),
+        };
+        let mut streams: Vec<(&Position, &Rng)> = self.streams.iter().collect();
+        streams.sort_by_key(|(square, _)| **square);
+        text += &format!("streams {}\n", streams.len());
+        for (square, rng) in streams {
+            text += &format!("{} {}\n", write_position(*square), rng.state());
+        }
+        text += &match self.propagation {
+            Propagation::Serial => "propagation serial\n".to_string(),
+            Propagation::Parallel { threads, min_wave } => format!("propagation parallel {} {}\n", threads, min_wave),
+        };
+        text += &format!("backtracks {} {}\n", self.backtracks, self.backtrack_limit);
+        text += &format!("tiles {}\n", self.tiles.len());
+        for tile in &self.tiles {
+            text += &write_tile(tile);
+            text += "\n";
+        }
+        text += &write_tags(&self.tags);
+        text += &format!("variants {}\n", variants.tiles.len());
+        for tile in &variants.tiles {
+            text += &write_tile(tile);
+            text += "\n";
+        }
+        text += &body;
+        text += "end\n";
+        text
+    }
+
+    /// Restores solver saved by `save`.
+    pub fn load(text: &str) -> Result<WFC, String> {
+        let mut tokens = Tokens::new(text);
+        tokens.expect("wfc-state")?;
+        let version: u32 = tokens.parse()?;
+        if version != SAVE_VERSION {
+            return Err(format!("error: unsupported save version {}, expected {}", version, SAVE_VERSION));
+        }
+
+        tokens.expect("seed")?;
+        let seed = tokens.parse()?;
+        tokens.expect("rng")?;
+        let rng = Rng::new(tokens.parse()?);
+        tokens.expect("rng_streams")?;
+        let rng_streams = match tokens.next_token()? {
+            "single" => RngStreams::Single,
+            "per_region" => RngStreams::PerRegion(tokens.position()?),
+            x => return Err(format!("error: unknown rng streams '{}'", x)),
+        };
+        tokens.expect("streams")?;
+        let mut streams = HashMap::new();
+        for _ in 0..tokens.parse::<usize>()? {
+            streams.insert(tokens.position()?, Rng::new(tokens.parse()?));
+        }
+        tokens.expect("propagation")?;
+        let propagation = match tokens.next_token()? {
+            "serial" => Propagation::Serial,
+            "parallel" => Propagation::Parallel { threads: tokens.parse()?, min_wave: tokens.parse()? },
+            x => return Err(format!("error: unknown propagation '{}'", x)),
+        };
+        tokens.expect("backtracks")?;
+        let (backtracks, backtrack_limit) = (tokens.parse()?, tokens.parse()?);
+        tokens.expect("tiles")?;
+        let tiles = (0..tokens.parse::<usize>()?).map(|_| tokens.tile()).collect::<Result<Vec<WfcTile>, String>>()?;
+
+        let mut tags = Tags::default();
+        while tokens.peek() == Some("tile_tags") || tokens.peek() == Some("socket_tags") {
+            let is_tile = tokens.next_token()? == "tile_tags";
+            let key: usize = tokens.parse()?;
+            for tag in tokens.strings()? {
+                if is_tile { tags.tag_tile(key as u32, &tag); } else { tags.tag_socket(key, &tag); }
+            }
+        }
+        tokens.expect("variants")?;
+        let variants = (0..tokens.parse::<usize>()?).map(|_| tokens.tile()).collect::<Result<Vec<WfcTile>, String>>()?;
+
+        tokens.expect("size")?;
+        let [x, y, z] = tokens.position()?;
+        let mut worldmap = Worldmap::new3d(x, y, z);
+        tokens.expect("active")?;
+        worldmap.active = tokens.flags()?;
+        if worldmap.active.len() != worldmap.len {
+            return Err(format!("error: mask has {} cells, expected {}", worldmap.active.len(), worldmap.len));
+        }
+        tokens.expect("mask_sockets")?;
+        worldmap.mask_sockets = match tokens.next_token()? {
+            "some" => Some(tokens.list()?),
+            "none" => None,
+            x => return Err(format!("error: unknown mask sockets '{}'", x)),
+        };
+        tokens.expect("boundaries")?;
+        for dir in 0..6 {
+            worldmap.boundaries[dir] = read_boundary(&mut tokens)?;
+        }
+        tokens.expect("stacks")?;
+        for i in 0..worldmap.len {
+            worldmap[i] = read_stack(&mut tokens, &variants)?;
+        }
+
+        tokens.expect("squares")?;
+        let squares_list = tokens.positions()?;
+        tokens.expect("squares_index")?;
+        let squares_index = tokens.parse()?;
+        tokens.expect("constraints")?;
+        let constraints = (0..tokens.parse::<usize>()?).map(|_| read_constraint(&mut tokens)).collect::<Result<Vec<Constraint>, String>>()?;
+        tokens.expect("trail")?;
+        let mut trail = Vec::new();
+        for _ in 0..tokens.parse::<usize>()? {
+            trail.push((tokens.position()?, read_stack(&mut tokens, &variants)?));
+        }
+        tokens.expect("steps")?;
+        let steps = read_steps(&mut tokens, &variants)?;
+        tokens.expect("undone")?;
+        let undone = read_steps(&mut tokens, &variants)?;
+        tokens.expect("end")?;
+
+        for square in squares_list.iter()
+            .chain(trail.iter().map(|(x, _)| x))
+            .chain(steps.iter().chain(undone.iter()).map(|x| &x.square)) {
+            if !worldmap.contains(*square) {
+                return Err(format!("error: square {:?} is outside of the worldmap", square));
+            }
+        }
+        for constraint in &constraints {
+            constraint.check(&worldmap)?;
+        }
+
+        Ok(WFC {
+            tiles,
+            worldmap,
+            seed,
+            rng,
+            rng_streams,
+            propagation,
+            streams,
+            squares_list,
+            squares_index,
+            constraints,
+            tags,
+            trail,
+            steps,
+            undone,
+            backtrack_limit,
+            backtracks,
+            explain: false,
+            reductions: Vec::new(),
+            contradiction: None,
+        })
+    }
+
+    pub fn save_to_file(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, self.save()).map_err(|e| format!("error: can't write '{}': {}", path, e))
+    }
+
+    pub fn load_from_file(path: &str) -> Result<WFC, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("error: can't read '{}': {}", path, e))?;
+        WFC::load(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilesets::{flat_city, flat_city_tags};
+
+    #[test]
+    fn resumes_where_it_stopped() {
+        let (_, tiles, _) = flat_city();
+        let mut wfc = WFC::init(Worldmap::new3d(8, 8, 1), tiles, 3);
+        wfc.tags = flat_city_tags();
+        wfc.add_constraint(Constraint::at_most(TileFilter::tag("road"), 20)).unwrap();
+        for _ in 0..10 {
+            wfc.wfc_step().unwrap();
+        }
+
+        let text = wfc.save();
+        let mut loaded = WFC::load(&text).unwrap();
+        assert_eq!(loaded.save(), text);
+        assert_eq!(wfc.run().is_ok(), loaded.run().is_ok());
+        assert_eq!(loaded.save(), wfc.save());
+    }
+
+    #[test]
+    fn rejects_constraints_outside_of_map() {
+        let (_, tiles, _) = flat_city();
+        let mut wfc = WFC::init(Worldmap::new3d(4, 4, 1), tiles, 3);
+        wfc.add_constraint(Constraint::Connected { sockets: vec![1], endpoints: vec![[3, 3, 0]] }).unwrap();
+        let text = wfc.save();
+        assert!(WFC::load(&text).is_ok());
+        assert!(WFC::load(&text.replace("connected 1 1 3,3,0", "connected 1 1 4,3,0")).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_tokens() {
+        let (_, tiles, _) = flat_city();
+        let mut wfc = WFC::init(Worldmap::new3d(4, 4, 1), tiles, 3);
+        wfc.wfc_step().unwrap();
+        let text = wfc.save();
+        assert!(WFC::load(&text).is_ok());
+        assert!(WFC::load(&text.replace(" solver\n", " walked\n")).is_err());
+        assert!(WFC::load(&text.replace("mask_sockets none", "mask_sockets all")).is_err());
+        assert!(WFC::load(&text.replace("wfc-state 1", "wfc-state 2")).is_err());
+    }
+
+    #[test]
+    fn writes_empty_lists() {
+        let constraint = Constraint::Restrict(Region::Mask(vec![]), TileFilter::Indices(vec![]));
+        let text = format!("{} {}", write_constraint(&constraint), write_flags(&[]));
+        let mut tokens = Tokens::new(&text);
+        assert_eq!(read_constraint(&mut tokens).unwrap(), constraint);
+        assert_eq!(tokens.flags().unwrap(), []);
+        assert!(tokens.is_done());
+    }
+}
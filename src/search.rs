@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::wfc::WFC;
+
+/// Which of the found seeds `SeedSearch::run` returns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchOrder {
+    /// Whichever seed finishes first, fastest but depends on thread timing.
+    First,
+    /// The lowest matching seed in the range, same result as trying seeds one by one.
+    LowestSeed,
+}
+
+/// Outcome of every tried seed.
+#[derive(Clone, Debug, Default)]
+pub struct SearchStats {
+    pub tried: u64,
+    pub successes: u64,
+    /// failed seeds with their errors, sorted by seed
+    pub failures: Vec<(u64, String)>,
+    /// number of failures per kind of error (error with numbers replaced by `#`)
+    pub errors: HashMap<String, u64>,
+    pub backtracks: usize,
+    pub is_cancelled: bool,
+}
+
+impl SearchStats {
+    fn add(&mut self, seed: u64, backtracks: usize, result: &Result<(), String>) {
+        self.tried += 1;
+        self.backtracks += backtracks;
+        match result {
+            Ok(()) => self.successes += 1,
+            Err(e) => {
+                self.failures.push((seed, e.clone()));
+                *self.errors.entry(error_kind(e)).or_default() += 1;
+            },
+        }
+    }
+}
+
+/// Error message with positions, counts etc. replaced by `#`, so errors can be grouped.
+pub fn error_kind(error: &str) -> String {
+    let mut kind = String::new();
+    for c in error.chars() {
+        if !c.is_ascii_digit() {
+            kind.push(c);
+        } else if !kind.ends_with('#') {
+            kind.push('#');
+        }
+    }
+    kind
+}
+
+/// Runs a prepared solver with many seeds on several threads, see `run`.
+pub struct SeedSearch {
+    pub seeds: Range<u64>,
+    pub threads: usize,
+    pub order: SearchOrder,
+    /// look for a seed which succeeds, or with `false` for one which fails (for debugging tilesets)
+    pub stop_on_success: bool,
+    /// set from another thread (e.g. a signal handler) to stop the search
+    pub cancel: Arc<AtomicBool>,
+}
+
+impl SeedSearch {
+    pub fn new(seeds: Range<u64>) -> SeedSearch {
+        SeedSearch {
+            seeds,
+            threads: std::thread::available_parallelism().map_or(1, |x| x.get()),
+            order: SearchOrder::LowestSeed,
+            stop_on_success: true,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Tries seeds of `wfc` (which may already have tiles added) until one of them succeeds
+    /// (or fails, see `stop_on_success`), the range ends or the search is cancelled.
+    /// Returns the seed with its solver, if any, and statistics of all tried seeds.
+    pub fn run(&self, wfc: &WFC) -> (Option<(u64, WFC)>, SearchStats) {
+        let next_seed = AtomicU64::new(self.seeds.start);
+        // lowest seed found so far, seeds above it don't need to be tried
+        let found_seed = AtomicU64::new(u64::MAX);
+        let found: Mutex<Option<(u64, WFC)>> = Mutex::new(None);
+        let stats = Mutex::new(SearchStats::default());
+
+        std::thread::scope(|scope| {
+            for _ in 0..self.threads.max(1) {
+                scope.spawn(|| loop {
+                    let seed = next_seed.fetch_add(1, Ordering::SeqCst);
+                    let is_found = match self.order {
+                        SearchOrder::First => found_seed.load(Ordering::SeqCst) != u64::MAX,
+                        SearchOrder::LowestSeed => seed > found_seed.load(Ordering::SeqCst),
+                    };
+                    if seed >= self.seeds.end || is_found || self.cancel.load(Ordering::Relaxed) {
+                        return;
+                    }
+
+                    let mut solver = wfc.clone();
+                    solver.init_rng(seed);
+                    let result = self.solve(&mut solver);
+                    if self.cancel.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    stats.lock().unwrap().add(seed, solver.backtracks, &result);
+
+                    if result.is_ok() == self.stop_on_success {
+                        let mut found = found.lock().unwrap();
+                        if found.as_ref().is_none_or(|(x, _)| *x >= seed) {
+                            found_seed.fetch_min(seed, Ordering::SeqCst);
+                            *found = Some((seed, solver));
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut stats = stats.into_inner().unwrap();
+        stats.failures.sort_by_key(|(seed, _)| *seed);
+        stats.is_cancelled = self.cancel.load(Ordering::Relaxed);
+        (found.into_inner().unwrap(), stats)
+    }
+
+    fn solve(&self, wfc: &mut WFC) -> Result<(), String> {
+        while !wfc.wfc_step()? {
+            if self.cancel.load(Ordering::Relaxed) {
+                return Err("error: cancelled".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wfc::Worldmap;
+    use crate::constraints::{Constraint, TileFilter};
+    use crate::tilesets::pipes;
+
+    #[test]
+    fn finds_lowest_seed() {
+        let (_, tiles, _) = pipes();
+        let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), tiles.clone(), 0);
+        wfc.add_constraint(Constraint::exactly(TileFilter::Indices(vec![tiles[3].index]), 3)).unwrap();
+        // seeds which have to backtrack fail
+        wfc.backtrack_limit = 0;
+        let fails = |seed| {
+            let mut solver = wfc.clone();
+            solver.init_rng(seed);
+            solver.run().is_err()
+        };
+        let failing: Vec<u64> = (0..20).filter(|&x| fails(x)).collect();
+        assert!(!failing.is_empty() && failing.len() < 20);
+
+        let mut search = SeedSearch::new(0..20);
+        search.threads = 4;
+        search.stop_on_success = false;
+        let (found, stats) = search.run(&wfc);
+        let (seed, solver) = found.unwrap();
+        assert_eq!(seed, failing[0]);
+        assert_eq!(solver.seed, seed);
+        // seeds up to the found one were all tried, failures are sorted by seed
+        assert!(stats.tried > seed);
+        assert_eq!(stats.successes as usize + stats.failures.len(), stats.tried as usize);
+        assert!(stats.failures.windows(2).all(|x| x[0].0 < x[1].0));
+        assert!(stats.failures.iter().all(|(x, _)| failing.contains(x)));
+        assert_eq!(stats.errors.values().sum::<u64>() as usize, stats.failures.len());
+
+        search.cancel.store(true, Ordering::Relaxed);
+        let (found, stats) = search.run(&wfc);
+        assert!(found.is_none());
+        assert!(stats.is_cancelled);
+    }
+}
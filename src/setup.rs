@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use crate::wfc::{WfcTile, Direction, WFC};
+use crate::save::{Tokens, read_boundary, read_constraint};
+
+/*
+Setup files describe what to put into a fresh solver before generating, in the same token
+syntax as save files (see save.rs), one statement after another:
+
+- boundary <side|all> <boundary>    side is north, east, south, west, up or down
+- surround <index> <angle>          fill map edges (only x/y edges of single layer maps)
+- tile <position> <index> <angle>   put a tile at a square
+- any constraint, e.g.  count 1 3 indices 5  or  restrict box 0,0,0 4,4,0 tags 1 road
+
+Boundaries apply before anything else, no matter where they are in the file.
+*/
+
+/// Variant of the tile with `index` rotated by `angle` degrees.
+fn find_tile(tiles: &Vec<WfcTile>, index: u32, angle: u32) -> Result<WfcTile, String> {
+    WFC::variants(tiles).into_iter()
+        .find(|x| x.index == index && x.angle == angle)
+        .ok_or_else(|| format!("error: no tile with index {} and angle {}", index, angle))
+}
+
+/// Applies a setup file to `wfc`, which should be fresh from `WFC::init`.
+pub fn apply_setup(wfc: &mut WFC, text: &str) -> Result<(), String> {
+    let mut tokens = Tokens::new(text);
+    let mut is_boundary_set = false;
+    while let Some(keyword) = tokens.peek() {
+        if keyword != "boundary" {
+            tokens.next_token()?;
+            continue;
+        }
+        tokens.next_token()?;
+        let side = tokens.next_token()?;
+        let boundary = read_boundary(&mut tokens)?;
+        match Direction::from_name(side) {
+            Some(dir) => wfc.worldmap.set_boundary(dir, boundary),
+            None if side == "all" => wfc.worldmap.set_boundaries(boundary),
+            None => return Err(format!("error: unknown side '{}'", side)),
+        }
+        is_boundary_set = true;
+    }
+    if is_boundary_set {
+        wfc.init_worldmap()?;
+    }
+
+    let mut tokens = Tokens::new(text);
+    while let Some(keyword) = tokens.peek() {
+        match keyword {
+            "boundary" => {
+                tokens.next_token()?;
+                tokens.next_token()?;
+                read_boundary(&mut tokens)?;
+            },
+            "surround" => {
+                tokens.next_token()?;
+                let tile = find_tile(&wfc.tiles, tokens.parse()?, tokens.parse()?)?;
+                if wfc.worldmap.size[2] == 1 {
+                    wfc.surround_worldmap_2d(&tile)?;
+                } else {
+                    wfc.surround_worldmap(&tile)?;
+                }
+            },
+            "tile" => {
+                tokens.next_token()?;
+                let square = tokens.position()?;
+                let tile = find_tile(&wfc.tiles, tokens.parse()?, tokens.parse()?)?;
+                wfc.add_tile(square, tile)?;
+            },
+            _ => wfc.add_constraint(read_constraint(&mut tokens)?)?,
+        }
+    }
+    Ok(())
+}
+
+/// Command line options of the binaries, `--name value` pairs by name.
+pub fn parse_args(args: &[String]) -> Result<HashMap<&str, &str>, String> {
+    let mut values = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let name = arg.strip_prefix("--").ok_or_else(|| format!("error: unexpected argument '{}'", arg))?;
+        let value = args.next().ok_or_else(|| format!("error: --{} needs a value", name))?;
+        values.insert(name, value.as_str());
+    }
+    Ok(values)
+}
+
+/// Non-zero sizes separated by `x`, like `10x10x1`.
+pub fn parse_sizes(name: &str, text: &str) -> Result<Vec<usize>, String> {
+    let sizes: Vec<usize> = text.split('x').map(|x| x.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("error: invalid {} '{}'", name, text))?;
+    if sizes.contains(&0) {
+        return Err(format!("error: invalid {} '{}'", name, text));
+    }
+    Ok(sizes)
+}
+
+/// Map size `XxY` or `XxYxZ`, a single layer if `Z` is left out.
+pub fn parse_size(text: &str) -> Result<[usize; 3], String> {
+    match parse_sizes("size", text)?[..] {
+        [x, y] => Ok([x, y, 1]),
+        [x, y, z] => Ok([x, y, z]),
+        _ => Err(format!("error: invalid size '{}', expected XxY or XxYxZ", text)),
+    }
+}
+
+pub fn parse_number<T: std::str::FromStr>(name: &str, text: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("error: invalid {} '{}'", name, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wfc::{Boundary, Worldmap};
+    use crate::tilesets::pipes;
+
+    #[test]
+    fn applies_setup() {
+        let (_, tiles, _) = pipes();
+        let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), tiles, 0);
+        let text = "
+            surround 2 0
+            tile 2,2,0 0 90   # T-junction
+            count 0 3 indices 4
+            boundary all fixed 0
+        ";
+        apply_setup(&mut wfc, text).unwrap();
+        assert_eq!(wfc.worldmap.boundaries[0], Boundary::Fixed(vec![0]));
+        assert_eq!(wfc.worldmap[(0, 0)][0].index, 2);
+        assert_eq!(wfc.worldmap[(2, 2)][0].angle, 90);
+        assert_eq!(wfc.constraints.len(), 1);
+        assert!(wfc.run().is_ok());
+
+        let mut wfc = WFC::init(Worldmap::new3d(6, 6, 1), pipes().1, 0);
+        assert!(apply_setup(&mut wfc, "tile 9,9,0 0 0").is_err());
+        assert!(apply_setup(&mut wfc, "connected 0 1 9,9,0").is_err());
+        assert!(apply_setup(&mut wfc, "restrict mask 0101 indices 2").is_err());
+        assert!(apply_setup(&mut wfc, "surround 0 45").is_err());
+    }
+
+    #[test]
+    fn parses_args() {
+        let args: Vec<String> = ["--size", "4x5", "--seed", "7"].iter().map(|x| x.to_string()).collect();
+        let values = parse_args(&args).unwrap();
+        assert_eq!(parse_size(values["size"]), Ok([4, 5, 1]));
+        assert_eq!(parse_number::<u64>("seed", values["seed"]), Ok(7));
+        assert!(parse_args(&args[..3]).is_err());
+        assert!(parse_args(&args[1..]).is_err());
+        assert!(parse_size("4x0").is_err());
+        assert!(parse_size("4").is_err());
+        assert!(parse_number::<u64>("seed", "x").is_err());
+    }
+}
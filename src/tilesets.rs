@@ -1,5 +1,36 @@
 
 use crate::wfc::{WfcTile, create_big_tile};
+use crate::constraints::Tags;
+
+/// Tileset with everything needed to generate and draw it.
+#[derive(Clone, Debug)]
+pub struct Tileset {
+    pub name: String,
+    /// tilemap image, tile `index` counts tiles row by row
+    pub image: String,
+    pub tiles: Vec<WfcTile>,
+    /// tiles per row of the tilemap image
+    pub tilemap_size: u32,
+    pub tags: Tags,
+}
+
+pub const TILESET_NAMES: [&str; 5] = ["pipes", "flat_city", "flat_city_paths_only", "stairs_3d", "stairs_3d_path"];
+
+/// Tileset by the name of its function.
+pub fn tileset(name: &str) -> Option<Tileset> {
+    let ((image, tiles, tilemap_size), tags) = match name {
+        "pipes" => (pipes(), Tags::default()),
+        "flat_city" => (flat_city(), flat_city_tags()),
+        "flat_city_paths_only" => (flat_city_paths_only(), flat_city_tags()),
+        "stairs_3d" => (stairs_3d(), Tags::default()),
+        "stairs_3d_path" => {
+            let (image, tiles, tilemap_size, _) = stairs_3d_path();
+            ((image, tiles, tilemap_size), Tags::default())
+        },
+        _ => return None,
+    };
+    Some(Tileset { name: name.to_string(), image, tiles, tilemap_size, tags })
+}
 
 pub fn pipes() -> (String, Vec<WfcTile>, u32) {
     let tilemap = String::from("./pipes_tileset.png");
@@ -157,6 +188,21 @@ pub fn flat_city() -> (String, Vec<WfcTile>, u32) {
     return (tilemap, tiles, 8);
 }
 
+/// Tags for `flat_city` and `flat_city_paths_only`.
+pub fn flat_city_tags() -> Tags {
+    let mut tags = Tags::default();
+    tags.tag_socket(0, "road")
+        .tag_socket(1, "wall");
+    // tile indices in flat-city.png
+    for index in [2, 3, 5, 13].iter() {
+        tags.tag_tile(*index, "road");
+    }
+    for index in [8, 9].iter() {
+        tags.tag_tile(*index, "wall");
+    }
+    tags
+}
+
 pub fn flat_city_paths_only() -> (String, Vec<WfcTile>, u32) {
     let tilemap = String::from("./flat-city.png");
 
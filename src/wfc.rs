@@ -1,7 +1,8 @@
 
-use std::collections::{HashSet};
-use rand::Rng;
-use rand::SeedableRng;
+use std::collections::{HashMap, HashSet};
+use crate::constraints::{Constraint, Region, TileFilter, Tags};
+use crate::rng::Rng;
+use crate::explain::{Cause, Reduction, Explanation};
 
 /*
 worldmap
@@ -59,8 +60,8 @@ impl PartialEq for WfcTile {
     }
 }
 
-fn choose_random<'a, Any>(rng: &mut rand::rngs::StdRng, vec: &'a Vec<Any>) -> &'a Any {
-    &vec[rng.gen_range(0..vec.len())]
+fn choose_random<'a, Any>(rng: &mut Rng, vec: &'a Vec<Any>) -> &'a Any {
+    &vec[rng.below(vec.len())]
 }
 
 pub fn create_big_tile(gen_con: &mut usize, size: (usize, usize, usize), big_tile: Vec<Option<(u32, [usize;6])>>) -> Vec<WfcTile> {
@@ -115,7 +116,6 @@ pub fn create_big_tile(gen_con: &mut usize, size: (usize, usize, usize), big_til
         // up
         if z-1 >= 0 && z-1 < z_size as i32 {
             let ppos = (y as usize * x_size) + ((z-1) as usize * x_size * y_size) + x as usize;
-            println!("pos {}  ppos {}  z-1 {}  z {}", pos, ppos, ((z-1) as usize * x_size * y_size), z);
             // generate connection
             if wfc_big_tile[ppos] != None {
                 wfc_big_tile[ pos].as_mut().unwrap().connection_types[5] = *gen_con; // down
@@ -127,12 +127,6 @@ pub fn create_big_tile(gen_con: &mut usize, size: (usize, usize, usize), big_til
     }
     }
 
-    for tile in &wfc_big_tile {
-        if let Some(tile) = tile {
-            println!("{:?}", tile);
-        }
-    }
-
     // add rotations
     // TODO: avoid duplicated rotations when tile is symmetrical.
     // TODO: something more elegant than multiplying connections by 1000, for example:
@@ -160,6 +154,28 @@ pub struct Worldmap {
     pub values: Vec<Vec<WfcTile>>,
     pub size: [usize; 3],
     pub len: usize,
+    /// Cells marked `false` are not part of the map, their stacks are always empty.
+    pub active: Vec<bool>,
+    /// Sockets allowed on faces touching an inactive cell, `None` allows any socket.
+    pub mask_sockets: Option<Vec<usize>>,
+    /// What lies beyond each side of the map, indexed by `Direction`.
+    pub boundaries: [Boundary; 6],
+}
+
+/// Sockets at or above this value are generated by `create_big_tile` and vertical rotations.
+pub const GENERATED_CONNECTIONS: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Boundary {
+    /// Any socket can face the edge.
+    Free,
+    /// Only the listed sockets can face the edge.
+    Fixed(Vec<usize>),
+    /// The map wraps around to the opposite side, which must be periodic too.
+    Periodic,
+    /// The map continues as its own mirror image. Tiles facing the edge meet their
+    /// own reflection, so big-tile and rotated vertical connections can't cross it.
+    Mirror,
 }
 
 impl Worldmap {
@@ -168,7 +184,105 @@ impl Worldmap {
             values: vec![vec![]; x*y*z],
             size: [x, y, z],
             len: x*y*z,
+            active: vec![true; x*y*z],
+            mask_sockets: None,
+            boundaries: [Boundary::Free, Boundary::Free, Boundary::Free, Boundary::Free, Boundary::Free, Boundary::Free],
+        }
+    }
+
+    /// Creates worldmap where only cells for which `is_active` returns true are generated.
+    pub fn with_mask<F: Fn(Position) -> bool>(x: usize, y: usize, z: usize, is_active: F) -> Worldmap {
+        let mut worldmap = Worldmap::new3d(x, y, z);
+        for zz in 0..z {
+        for yy in 0..y {
+        for xx in 0..x {
+            let pos = [xx, yy, zz];
+            let idx = worldmap.index_of(pos);
+            worldmap.active[idx] = is_active(pos);
+        }
+        }
+        }
+        worldmap
+    }
+
+    /// Creates worldmap from 2d bitmap (indexed as `bitmap[y][x]`) extruded `z` layers up.
+    /// Rows shorter than the widest row are padded with inactive cells.
+    pub fn from_bitmap(bitmap: &[Vec<bool>], z: usize) -> Worldmap {
+        let y = bitmap.len();
+        let x = bitmap.iter().map(|row| row.len()).max().unwrap_or(0);
+        Worldmap::with_mask(x, y, z, |[xx, yy, _]| *bitmap[yy].get(xx).unwrap_or(&false))
+    }
+
+    pub fn set_mask_sockets(&mut self, sockets: Option<Vec<usize>>) {
+        self.mask_sockets = sockets;
+    }
+
+    /// Sets boundary for one side of the map. Periodic boundaries are always set in pairs,
+    /// so making one side periodic also wraps the opposite side and vice versa.
+    pub fn set_boundary(&mut self, dir: Direction, boundary: Boundary) {
+        let opposite: usize = dir.flip().into();
+        if boundary == Boundary::Periodic {
+            self.boundaries[opposite] = Boundary::Periodic;
+        } else if self.boundaries[opposite] == Boundary::Periodic {
+            self.boundaries[opposite] = Boundary::Free;
+        }
+        let dir: usize = dir.into();
+        self.boundaries[dir] = boundary;
+    }
+
+    pub fn set_boundaries(&mut self, boundary: Boundary) {
+        for i in 0..6 {
+            self.boundaries[i] = boundary.clone();
+        }
+    }
+
+    pub fn index_of(&self, pos: Position) -> usize {
+        let [x, y, z] = pos;
+        x + y*self.size[0] + z*self.size[0]*self.size[1]
+    }
+
+    /// Position lies within the worldmap size, whether its cell is active or not.
+    pub fn contains(&self, pos: Position) -> bool {
+        (0..3).all(|i| pos[i] < self.size[i])
+    }
+
+    pub fn is_active(&self, pos: Position) -> bool {
+        self.active[self.index_of(pos)]
+    }
+
+    /// Checks `tile` sockets against `mask_sockets` on every face of `square` touching an inactive cell.
+    pub fn fits_mask(&self, square: Position, tile: &WfcTile) -> bool {
+        let sockets = match &self.mask_sockets {
+            Some(x) => x,
+            None => return true,
+        };
+        for i in 0..6 {
+            if let Some(next) = self.move_(square, &Direction::from(i)) {
+                if !self.is_active(next) && !sockets.contains(&tile.connection_types[i]) {
+                    return false;
+                }
+            }
         }
+        true
+    }
+
+    /// Checks `tile` sockets on every face of `square` lying on the map edge or touching an inactive cell.
+    pub fn fits_boundary(&self, square: Position, tile: &WfcTile) -> bool {
+        for i in 0..6 {
+            if self.move_(square, &Direction::from(i)).is_some() {
+                continue;
+            }
+            let socket = tile.connection_types[i];
+            let is_ok = match &self.boundaries[i] {
+                Boundary::Free | Boundary::Periodic => true,
+                Boundary::Fixed(sockets) => sockets.contains(&socket),
+                Boundary::Mirror => socket < GENERATED_CONNECTIONS,
+            };
+            if !is_ok {
+                return false;
+            }
+        }
+        self.fits_mask(square, tile)
     }
 
     pub fn move_(&self, square: Position, dir: &Direction) -> Option<Position> {
@@ -182,9 +296,15 @@ impl Worldmap {
         };
         let [XS,YS,ZS] = self.size;
         let [x, y, z] = square;
-        let x = x as i32 + dx;
-        let y = y as i32 + dy;
-        let z = z as i32 + dz;
+        let mut x = x as i32 + dx;
+        let mut y = y as i32 + dy;
+        let mut z = z as i32 + dz;
+        let side: usize = dir.clone().into();
+        if self.boundaries[side] == Boundary::Periodic {
+            x = x.rem_euclid(XS as i32);
+            y = y.rem_euclid(YS as i32);
+            z = z.rem_euclid(ZS as i32);
+        }
         if x < 0 || x as usize >= XS { return None; }
         if y < 0 || y as usize >= YS { return None; }
         if z < 0 || z as usize >= ZS { return None; }
@@ -252,7 +372,7 @@ impl std::ops::IndexMut<Position> for Worldmap {
 }
 
 
-type Position = [usize; 3];
+pub type Position = [usize; 3];
 
 #[derive(Debug, Clone)]
 pub enum Direction {NORTH, EAST, SOUTH, WEST, UP, DOWN}
@@ -268,6 +388,23 @@ impl Direction {
             Direction::DOWN  => Direction::UP,
         }
     }
+
+    /// Side name used in setup files and messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::NORTH => "north",
+            Direction::EAST  => "east",
+            Direction::SOUTH => "south",
+            Direction::WEST  => "west",
+            Direction::UP    => "up",
+            Direction::DOWN  => "down",
+        }
+    }
+
+    /// Direction with side `name`, see `name`.
+    pub fn from_name(name: &str) -> Option<Direction> {
+        (0..6).map(Direction::from).find(|x| x.name() == name)
+    }
 }
 
 impl From<usize> for Direction {
@@ -297,13 +434,116 @@ impl Into<usize> for Direction {
     }
 }
 
+/// Where random tile choices come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RngStreams {
+    /// One generator for the whole map, every choice depends on all choices made before it.
+    Single,
+    /// Separate generator for every block of the given size (`[1, 1, 1]` is one per square),
+    /// derived from the seed and block position. Choices inside a block don't depend on how many
+    /// choices were made elsewhere, so editing one part of the map or changing the order squares
+    /// are visited in doesn't reshuffle the rest of it.
+    PerRegion([usize; 3]),
+}
+
+/// How `WFC::propagate` spreads a change through the worldmap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Propagation {
+    /// Depth-first, one square at a time.
+    Serial,
+    /// Breadth-first in waves, squares next to a wave are updated on `threads` threads, waves
+    /// smaller than `min_wave` squares on the calling thread. Ends with the same stacks as `Serial`
+    /// (only an error may name another square), so a seed gives the same worldmap in both modes.
+    /// Pays off on big 3d maps.
+    Parallel { threads: usize, min_wave: usize },
+}
+
+impl Propagation {
+    /// Parallel propagation with waves of the default minimal size.
+    pub fn parallel(threads: usize) -> Propagation {
+        Propagation::Parallel { threads, min_wave: PARALLEL_MIN_WAVE }
+    }
+}
+
+/// Why `WFC::collapse_at` or `WFC::ban` refused a change. The worldmap is left as it was.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CollapseError {
+    OutOfBounds(Position),
+    MaskedOut(Position),
+    /// tile is not (or no longer) possible at the square
+    NotInDomain(Position, WfcTile),
+    /// banning the tile would leave the square empty
+    LastTile(Position, WfcTile),
+    /// the change made some square impossible, with the propagation error
+    Contradiction(Position, String),
+}
+
+impl std::fmt::Display for CollapseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CollapseError::OutOfBounds(square) => write!(f, "error: square {:?} is outside of the worldmap", square),
+            CollapseError::MaskedOut(square) => write!(f, "error: square {:?} is masked out", square),
+            CollapseError::NotInDomain(square, tile) => write!(f, "error: tile {:?} is not possible at square {:?}", tile, square),
+            CollapseError::LastTile(square, tile) => write!(f, "error: tile {:?} is the last one left at square {:?}", tile, square),
+            CollapseError::Contradiction(square, e) => write!(f, "error: change at square {:?} was rolled back: {}", square, e),
+        }
+    }
+}
+
+impl std::error::Error for CollapseError {}
+
+impl From<CollapseError> for String {
+    fn from(e: CollapseError) -> String {
+        e.to_string()
+    }
+}
+
+/// Default `min_wave` of parallel propagation, smaller waves don't pay for starting threads.
+pub const PARALLEL_MIN_WAVE: usize = 256;
+
+/// Collapse made by `wfc_step`, kept so it can be undone when backtracking.
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub square: Position,
+    pub tile: WfcTile,
+    /// `squares_index` pointing at `square`, or for manual steps the cursor when they were made
+    pub squares_index: usize,
+    /// length of the trail before this step
+    pub trail_len: usize,
+    /// made by `collapse_at` rather than by the solver, so it doesn't move the cursor
+    pub is_manual: bool,
+}
+
+/// Solver. Results are deterministic: the same tiles, worldmap, constraints and seed always give
+/// the same worldmap, on any platform (see `Rng`).
+#[derive(Clone)]
 pub struct WFC {
     pub tiles: Vec<WfcTile>,
     pub worldmap: Worldmap,
     pub seed: u64,
-    rng: rand::rngs::StdRng,
+    pub(crate) rng: Rng,
+    pub rng_streams: RngStreams,
+    pub propagation: Propagation,
+    /// generators of `RngStreams::PerRegion` blocks, created on first use
+    pub(crate) streams: HashMap<Position, Rng>,
     pub squares_list: Vec<[usize;3]>,
-    squares_index: usize,
+    pub(crate) squares_index: usize,
+    /// Constraints re-applied after every step, see `restrict`.
+    pub constraints: Vec<Constraint>,
+    pub tags: Tags,
+    /// Previous stacks of every square changed since the first step, newest last.
+    pub(crate) trail: Vec<(Position, Vec<WfcTile>)>,
+    pub steps: Vec<Step>,
+    /// Steps taken back by `undo_step`, newest last, cleared by any new step.
+    pub undone: Vec<Step>,
+    /// How many times a run may backtrack before giving up.
+    pub backtrack_limit: usize,
+    pub backtracks: usize,
+    /// Log reductions of the current step, so contradictions can be explained (slower).
+    pub explain: bool,
+    pub reductions: Vec<Reduction>,
+    /// Explanation of the last contradiction found while `explain` was set.
+    pub contradiction: Option<Explanation>,
 }
 
 impl WFC {
@@ -313,19 +553,38 @@ impl WFC {
         let mut wfc = WFC {
             tiles,
             worldmap,
-            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            rng: Rng::new(seed),
+            rng_streams: RngStreams::Single,
+            propagation: Propagation::Serial,
+            streams: HashMap::new(),
             seed,
             squares_list: (0..ZS).map(|z|(0..YS).map(move |y|(0..XS).map(move |x|[x,y,z]))).flatten().flatten().collect(),
             squares_index: 0,
+            constraints: Vec::new(),
+            tags: Tags::default(),
+            trail: Vec::new(),
+            steps: Vec::new(),
+            undone: Vec::new(),
+            backtrack_limit: 1000,
+            backtracks: 0,
+            explain: false,
+            reductions: Vec::new(),
+            contradiction: None,
         };
-        wfc.init_worldmap();
+        // boundaries which can't be met are found again by `feasibility::check` or while solving
+        let _ = wfc.init_worldmap();
         wfc
     }
 
     pub fn init_rng(&mut self, seed: u64) {
         self.seed = seed;
-        self.rng = rand::rngs::StdRng::seed_from_u64(seed);
+        self.rng = Rng::new(seed);
+        self.streams.clear();
         self.squares_index = 0;
+        self.trail.clear();
+        self.steps.clear();
+        self.undone.clear();
+        self.backtracks = 0;
     }
 
     fn init_tile(tiles: &Vec<WfcTile>, square: &mut Vec<WfcTile>) {
@@ -360,11 +619,40 @@ impl WFC {
         }
     }
 
-    pub fn init_worldmap(&mut self) {
+    /// All variants of `tiles` as they appear in a fresh stack (rotations included).
+    pub fn variants(tiles: &Vec<WfcTile>) -> Vec<WfcTile> {
+        let mut variants = Vec::new();
+        WFC::init_tile(tiles, &mut variants);
+        variants
+    }
+
+    /// Fills every active square with all tiles fitting the boundaries and propagates the boundaries.
+    /// Fails if the boundaries can't be met, the worldmap is then only partly propagated.
+    pub fn init_worldmap(&mut self) -> Result<(), String> {
         // fill worldmap with stuff
         for i in 0..self.worldmap.len {
+            if !self.worldmap.active[i] {
+                self.worldmap[i].clear();
+                continue;
+            }
             WFC::init_tile(&self.tiles, &mut self.worldmap[i]);
         }
+
+        // faces on the map edge and next to masked-out cells only accept
+        // sockets allowed by the boundary
+        let mut shrunk = Vec::new();
+        for square in self.squares_list.clone() {
+            let worldmap = &self.worldmap;
+            let stack: Vec<WfcTile> = worldmap[square].iter().filter(|tile| worldmap.fits_boundary(square, tile)).cloned().collect();
+            if stack.len() < worldmap[square].len() {
+                shrunk.push(square);
+            }
+            self.worldmap[square] = stack;
+        }
+        for square in shrunk {
+            self.propagate(square)?;
+        }
+        Ok(())
     }
 
     pub fn surround_worldmap(&mut self, tile: &WfcTile) -> Result<(), String> {
@@ -400,7 +688,10 @@ impl WFC {
         for x in 0..XS {
             for y in 0..YS {
                 for z in 0..ZS {
-                    let tile = self.worldmap[(x,y,z)][0];
+                    let tile = match self.worldmap[(x,y,z)].first() {
+                        Some(x) => *x,
+                        None => continue,
+                    };
                     if tile.index == 0 { continue; }
                     print!("({}, {}, {}, {}, {}), ", x, y, z, tile.angle, tile.index);
                 }
@@ -413,48 +704,338 @@ impl WFC {
         loop {
             let square = self.squares_list.get(self.squares_index)?;
             self.squares_index += 1;
-            if self.worldmap[*square].len() == 1 {
+            if !self.worldmap.is_active(*square) || self.worldmap[*square].len() == 1 {
                 continue;
             }
             return Some(*square)
         }
     }
 
+    /// Puts `tile` at `square` whether it is in its stack or not, for setting up a worldmap.
+    /// During generation use `collapse_at`, which checks the tile and can be undone.
     pub fn add_tile(&mut self, square: Position, tile: WfcTile) -> Result<(), String> {
-        self.worldmap[square].clear();
-        self.worldmap[square].push(tile);
+        if !self.worldmap.contains(square) {
+            return Err(format!("error: square {:?} is outside of the map", square));
+        }
+        if !self.worldmap.is_active(square) {
+            return Err(format!("error: square {:?} is masked out", square));
+        }
+        if !self.worldmap.fits_boundary(square, &tile) {
+            return Err(format!("error: tile {:?} doesn't fit boundary sockets at square {:?}", tile, square));
+        }
+        self.set_stack(square, vec![tile]);
+        self.record(square, Cause::Collapse(tile), 1);
 
         self.propagate(square)?;
 
         return Ok(());
     }
 
-    pub fn wfc_step(&mut self) -> Result<bool, String> {
-        let mut worldmap_copy = self.worldmap.clone();
+    /// Replaces stack of `square`, remembering the old one so it can be restored when backtracking.
+    pub fn set_stack(&mut self, square: Position, stack: Vec<WfcTile>) {
+        let old_stack = std::mem::replace(&mut self.worldmap[square], stack);
+        if !self.steps.is_empty() {
+            self.trail.push((square, old_stack));
+        }
+    }
+
+    /// Keeps only tiles of `square` for which `keep` returns true and propagates the change.
+    /// Returns true if the stack changed.
+    pub fn retain<F: Fn(&WfcTile) -> bool>(&mut self, square: Position, keep: F) -> Result<bool, String> {
+        let stack: Vec<WfcTile> = self.worldmap[square].iter().filter(|tile| keep(tile)).cloned().collect();
+        if stack.len() == self.worldmap[square].len() {
+            return Ok(false);
+        }
+        if stack.is_empty() {
+            self.record(square, Cause::Restriction, 0);
+            return Err(self.contradiction_error(format!("error: no tiles left for square {:?}", square), square));
+        }
+        self.record(square, Cause::Restriction, stack.len());
+        self.set_stack(square, stack);
+        self.propagate(square)?;
+        Ok(true)
+    }
+
+    /// Logs why the stack of `square` shrank to `left` tiles, when `explain` is set.
+    fn record(&mut self, square: Position, cause: Cause, left: usize) {
+        if self.explain {
+            self.reductions.push(Reduction { square, cause, left });
+        }
+    }
+
+    /// Adds explanation of the emptied stack of `square` to `message`, when `explain` is set.
+    fn contradiction_error(&mut self, message: String, square: Position) -> String {
+        if !self.explain {
+            return message;
+        }
+        let explanation = Explanation::new(&self.reductions, square);
+        let message = format!("{}\n{}", message, explanation);
+        self.contradiction = Some(explanation);
+        message
+    }
 
+    fn undo_trail(&mut self, trail_len: usize) {
+        while self.trail.len() > trail_len {
+            let (square, stack) = self.trail.pop().unwrap();
+            self.worldmap[square] = stack;
+        }
+    }
+
+    /// Restricts cells inside `region` to tiles matching `filter`.
+    /// Can be called before or during solving, the restriction is kept and re-applied after every step.
+    pub fn restrict(&mut self, region: Region, filter: TileFilter) -> Result<(), String> {
+        self.add_constraint(Constraint::Restrict(region, filter))
+    }
+
+    /// Adds a constraint kept for the rest of the run, fails if it refers to squares outside of the worldmap
+    /// or can't be satisfied. On error the worldmap is rolled back and nothing changes.
+    pub fn add_constraint(&mut self, constraint: Constraint) -> Result<(), String> {
+        constraint.check(&self.worldmap)?;
+        // before the first step changes aren't recorded in the trail
+        let trail_len = self.trail.len();
+        let worldmap = if self.steps.is_empty() { Some(self.worldmap.clone()) } else { None };
+        self.constraints.push(constraint);
+        let result = self.apply_constraints();
+        if result.is_err() {
+            self.constraints.pop();
+            match worldmap {
+                Some(x) => self.worldmap = x,
+                None => self.undo_trail(trail_len),
+            }
+        }
+        result
+    }
+
+    /// Resets squares of `region` to every tile fitting their boundary, restricted only by the
+    /// squares around the region, so `run` solves the region again while the rest stays as it is.
+    /// Steps made so far can't be undone afterwards.
+    pub fn uncollapse(&mut self, region: &Region) -> Result<(), String> {
+        self.squares_index = 0;
+        self.trail.clear();
+        self.steps.clear();
+        self.backtracks = 0;
+
+        let mut all_tiles = Vec::new();
+        WFC::init_tile(&self.tiles, &mut all_tiles);
+        let squares = region.squares(&self.worldmap);
+        let mut shrunk = Vec::new();
+        for &square in &squares {
+            let worldmap = &self.worldmap;
+            let stack: Vec<WfcTile> = all_tiles.iter().filter(|tile| worldmap.fits_boundary(square, tile)).cloned().collect();
+            if stack.len() < all_tiles.len() {
+                shrunk.push(square);
+            }
+            self.worldmap[square] = stack;
+        }
+        for &square in &squares {
+            for dir in 0..6 {
+                let next = match self.worldmap.move_(square, &Direction::from(dir)) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if self.worldmap.is_active(next) && !region.contains(&self.worldmap, next) {
+                    self.propagate(next)?;
+                }
+            }
+        }
+        for square in shrunk {
+            self.propagate(square)?;
+        }
+        self.apply_constraints()
+    }
+
+    /// Solves `region` of a solved worldmap again with `seed`. When the region can't be solved
+    /// against its surroundings it is widened by one square at a time, up to `max_widen` squares.
+    /// Returns the region which was solved, the worldmap is left unchanged on error.
+    /// `seed` of the solver itself is kept either way.
+    pub fn regenerate(&mut self, region: &Region, seed: u64, max_widen: usize) -> Result<Region, String> {
+        let worldmap = self.worldmap.clone();
+        let original_seed = self.seed;
+        let mut last_error = String::new();
+        for widen in 0..=max_widen {
+            let region = region.grow(&worldmap, widen);
+            self.init_rng(seed);
+            let result = self.uncollapse(&region).and_then(|_| self.run().map(|_| ()));
+            match result {
+                Ok(()) => {
+                    self.seed = original_seed;
+                    return Ok(region);
+                },
+                Err(e) => last_error = e,
+            }
+            self.worldmap = worldmap.clone();
+        }
+        self.init_rng(original_seed);
+        Err(format!("error: can't regenerate region widened by up to {} squares, last error: {}", max_widen, last_error))
+    }
+
+    /// Counts collapsed squares holding a tile matching `filter`.
+    pub fn count(&self, filter: &TileFilter) -> usize {
+        self.find(filter).len()
+    }
+
+    /// Finds collapsed squares holding a tile matching `filter`.
+    pub fn find(&self, filter: &TileFilter) -> Vec<Position> {
+        self.squares_list.iter()
+            .filter(|&&square| self.worldmap[square].len() == 1 && filter.matches(&self.tags, &self.worldmap[square][0]))
+            .cloned()
+            .collect()
+    }
+
+    /// Applies constraints until none of them changes the worldmap.
+    pub fn apply_constraints(&mut self) -> Result<(), String> {
         loop {
-            let square = match self.collapse() {
+            let mut is_changed = false;
+            for constraint in self.constraints.clone() {
+                is_changed |= constraint.apply(self)?;
+            }
+            if !is_changed {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn wfc_step(&mut self) -> Result<bool, String> {
+        self.undone.clear();
+        self.reductions.clear();
+        let mut result = match self.collapse() {
+            Some(square) => self.propagate(square).and_then(|_| self.apply_constraints()),
+            None => return Ok(true),
+        };
+
+        while let Err(e) = result {
+            // backtrack / error recovery
+            let step = match self.steps.pop() {
                 Some(x) => x,
-                None => return Ok(true),
+                None => return Err(e),
             };
+            if self.backtracks >= self.backtrack_limit {
+                return Err(format!("error: gave up after {} backtracks, last error: {}", self.backtracks, e));
+            }
+            self.backtracks += 1;
+            self.undo_trail(step.trail_len);
+            self.reductions.clear();
+            self.squares_index = step.squares_index;
+            eprintln!("Backtracking for square {:?}", step.square);
+
+            // remove selected tile from list of available, if that fails too
+            // we backtrack further on the next iteration
+            let mut stack = self.worldmap[step.square].clone();
+            stack.retain(|&x| x != step.tile);
+            if stack.is_empty() {
+                result = Err(format!("error: collapse of square {:?} resulted in an empty stack after trying all available tiles", step.square));
+                continue;
+            }
+            self.set_stack(step.square, stack);
+            result = self.propagate(step.square).and_then(|_| self.apply_constraints());
+        }
+        Ok(false)
+    }
 
-            match self.propagate(square) {
-                Ok(_) => return Ok(false),
-                Err(_) => {
-                    // backtrack / error recovery
+    fn check_domain(&self, square: Position, tile: &WfcTile) -> Result<(), CollapseError> {
+        if !self.worldmap.contains(square) {
+            return Err(CollapseError::OutOfBounds(square));
+        }
+        if !self.worldmap.is_active(square) {
+            return Err(CollapseError::MaskedOut(square));
+        }
+        if !self.worldmap[square].contains(tile) {
+            return Err(CollapseError::NotInDomain(square, *tile));
+        }
+        Ok(())
+    }
 
-                    if worldmap_copy[square].len() == 1 {
-                        return Err(format!("error: collapse of square {:?} resulted in an empty stack after trying all available tiles", square));
-                    }
+    /// Collapses `square` to `tile` chosen by the caller, as a step which can be undone.
+    /// On contradiction the worldmap is rolled back and nothing changes.
+    pub fn collapse_at(&mut self, square: Position, tile: WfcTile) -> Result<(), CollapseError> {
+        self.check_domain(square, &tile)?;
+        self.undone.clear();
+        self.steps.push(Step {
+            square,
+            tile,
+            squares_index: self.squares_index,
+            trail_len: self.trail.len(),
+            is_manual: true,
+        });
+        self.set_stack(square, vec![tile]);
+        self.record(square, Cause::Collapse(tile), 1);
+        if let Err(e) = self.propagate(square).and_then(|_| self.apply_constraints()) {
+            let step = self.steps.pop().unwrap();
+            self.undo_trail(step.trail_len);
+            return Err(CollapseError::Contradiction(square, e));
+        }
+        Ok(())
+    }
 
-                    // remove selected tile from list of available
-                    worldmap_copy[square].retain(|&x| x != self.worldmap[square][0]);
-                    self.worldmap = worldmap_copy.clone();
-                    self.squares_index -= 1;
-                    println!("Backtracking for square {:?}", square);
-                },
-            };
+    /// Removes `tile` from the stack of `square`. On contradiction the worldmap is rolled back.
+    pub fn ban(&mut self, square: Position, tile: WfcTile) -> Result<(), CollapseError> {
+        self.check_domain(square, &tile)?;
+        if self.worldmap[square].len() == 1 {
+            return Err(CollapseError::LastTile(square, tile));
+        }
+        // before the first step changes aren't recorded in the trail
+        let trail_len = self.trail.len();
+        let worldmap = if self.steps.is_empty() { Some(self.worldmap.clone()) } else { None };
+        if let Err(e) = self.retain(square, |&x| x != tile).and_then(|_| self.apply_constraints()) {
+            match worldmap {
+                Some(x) => self.worldmap = x,
+                None => self.undo_trail(trail_len),
+            }
+            return Err(CollapseError::Contradiction(square, e));
         }
+        Ok(())
+    }
+
+    /// Takes back the last collapse (with everything it propagated), it can be made again
+    /// with `redo_step`. Returns false if there is nothing to undo.
+    pub fn undo_step(&mut self) -> bool {
+        let step = match self.steps.pop() {
+            Some(x) => x,
+            None => return false,
+        };
+        self.undo_trail(step.trail_len);
+        self.squares_index = step.squares_index;
+        self.undone.push(step);
+        true
+    }
+
+    /// Makes the last collapse taken back by `undo_step` again.
+    /// Returns false if there is nothing to redo.
+    pub fn redo_step(&mut self) -> Result<bool, String> {
+        let step = match self.undone.pop() {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        self.squares_index = if step.is_manual { step.squares_index } else { step.squares_index + 1 };
+        self.steps.push(Step { trail_len: self.trail.len(), ..step.clone() });
+        self.set_stack(step.square, vec![step.tile]);
+        if let Err(e) = self.propagate(step.square).and_then(|_| self.apply_constraints()) {
+            self.undo_step();
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Takes back the last collapse and makes a new one at the same square with any other tile,
+    /// starting a new branch of the generation. Redo history is dropped.
+    /// On error nothing changes, redo history included.
+    pub fn branch_step(&mut self) -> Result<bool, String> {
+        if self.steps.is_empty() {
+            return Ok(false);
+        }
+        // backtracking in `wfc_step` may take back more steps, so keep the whole state
+        let snapshot = self.clone();
+        self.undo_step();
+        let step = self.undone.pop().unwrap();
+        self.undone.clear();
+        let result = self.retain(step.square, |&tile| tile != step.tile)
+            .and_then(|_| self.apply_constraints())
+            .and_then(|_| self.wfc_step());
+        if let Err(e) = result {
+            *self = snapshot;
+            return Err(e);
+        }
+        Ok(true)
     }
 
     pub fn collapse(&mut self) -> Option<Position> {
@@ -467,22 +1048,43 @@ impl WFC {
 //        let square = available_squares[0];
 
         // observe
-        let selected_tile = *choose_random(&mut self.rng, &self.worldmap[square]);
+        let rng = match self.rng_streams {
+            RngStreams::Single => &mut self.rng,
+            RngStreams::PerRegion(block) => {
+                let key = [square[0] / block[0], square[1] / block[1], square[2] / block[2]];
+                let seed = self.seed;
+                self.streams.entry(key).or_insert_with(|| Rng::stream(seed, &key.map(|x| x as u64)))
+            },
+        };
+        let selected_tile = *choose_random(rng, &self.worldmap[square]);
 
         //println!("selected_tile: {:?}  square: {:?}  stack: {:?}", selected_tile, square, self.worldmap[square].len());
-        self.worldmap[square].clear();
-        self.worldmap[square].push(selected_tile);
+        self.steps.push(Step {
+            square,
+            tile: selected_tile,
+            squares_index: self.squares_index - 1,
+            trail_len: self.trail.len(),
+            is_manual: false,
+        });
+        self.set_stack(square, vec![selected_tile]);
+        self.record(square, Cause::Collapse(selected_tile), 1);
         return Some(square);
     }
 
     /// returns true if we changed available connections, false otherwise
     fn update_tile_stack(&mut self, connections: &HashSet<usize>, square: Position, dir: Direction) -> Result<bool, String> {
+        let from = square;
         // we are trying to access tile beyond edge
         let square = match self.worldmap.move_(square, &dir) {
             Some(x) => x,
             None => return Ok(false),
         };
 
+        // masked-out cells are not part of the map
+        if !self.worldmap.is_active(square) {
+            return Ok(false);
+        }
+
         // this direction has all connections available
         // FIXME: 3 is hardcoded, but should be calculated.
         //        probably should put it into WFC struct.
@@ -506,11 +1108,16 @@ impl WFC {
             //println!("  stack didn't change");
             return Ok(false);
         }
+        if self.explain {
+            let mut sockets: Vec<usize> = connections.iter().cloned().collect();
+            sockets.sort();
+            self.record(square, Cause::Neighbour { from, side: dir.into(), sockets }, ok_stack.len());
+        }
         if ok_stack.len() == 0 {
             //println!("  stack empty");
-            return Err(format!("error: tile-stack reduced to 0!!!  tile: {:?}", square));
+            return Err(self.contradiction_error(format!("error: tile-stack reduced to 0!!!  tile: {:?}", square), square));
         }
-        self.worldmap[square] = ok_stack;
+        self.set_stack(square, ok_stack);
 //        println!("update_tile_stack has changed connections {:?}", map_square);
         //println!("  stack changed");
         return Ok(true);
@@ -531,8 +1138,15 @@ impl WFC {
         return connections;
     }
 
-    // wfc3d: map_square probably can be anything
     pub fn propagate(&mut self, square: Position) -> Result<(), String> {
+        match self.propagation {
+            Propagation::Serial => self.propagate_serial(square),
+            Propagation::Parallel { threads, min_wave } => self.propagate_parallel(square, threads, min_wave),
+        }
+    }
+
+    // wfc3d: map_square probably can be anything
+    fn propagate_serial(&mut self, square: Position) -> Result<(), String> {
         let connections = self.gather_available_connections(square);
         //println!("propagate {:?} connections: {:?}", square, connections);
 
@@ -545,16 +1159,91 @@ impl WFC {
         let is_recurse5 = self.update_tile_stack(&connections[5], square, Direction::DOWN)?;
 
         // recurse in that direction
-        if is_recurse0 { self.propagate(self.worldmap.move_(square, &Direction::NORTH).unwrap())?; }
-        if is_recurse1 { self.propagate(self.worldmap.move_(square, &Direction::EAST).unwrap())?; }
-        if is_recurse2 { self.propagate(self.worldmap.move_(square, &Direction::SOUTH).unwrap())?; }
-        if is_recurse3 { self.propagate(self.worldmap.move_(square, &Direction::WEST).unwrap())?; }
-        if is_recurse4 { self.propagate(self.worldmap.move_(square, &Direction::UP).unwrap())?; }
-        if is_recurse5 { self.propagate(self.worldmap.move_(square, &Direction::DOWN).unwrap())?; }
+        if is_recurse0 { self.propagate_serial(self.worldmap.move_(square, &Direction::NORTH).unwrap())?; }
+        if is_recurse1 { self.propagate_serial(self.worldmap.move_(square, &Direction::EAST).unwrap())?; }
+        if is_recurse2 { self.propagate_serial(self.worldmap.move_(square, &Direction::SOUTH).unwrap())?; }
+        if is_recurse3 { self.propagate_serial(self.worldmap.move_(square, &Direction::WEST).unwrap())?; }
+        if is_recurse4 { self.propagate_serial(self.worldmap.move_(square, &Direction::UP).unwrap())?; }
+        if is_recurse5 { self.propagate_serial(self.worldmap.move_(square, &Direction::DOWN).unwrap())?; }
 
         return Ok(());
     }
 
+    // Squares changed by the previous wave restrict their neighbours, neighbours which changed
+    // form the next wave. Each neighbour is only checked against changed squares, same as in
+    // `propagate_serial`, so both end in the same fixpoint.
+    fn propagate_parallel(&mut self, square: Position, threads: usize, min_wave: usize) -> Result<(), String> {
+        let mut wave = vec![square];
+        while !wave.is_empty() {
+            // neighbours of the wave with sides facing a changed square
+            let mut neighbours: Vec<(Position, [bool; 6])> = Vec::new();
+            let mut neighbour_index = HashMap::new();
+            for &changed in &wave {
+                for dir in 0..6 {
+                    let next = match self.worldmap.move_(changed, &Direction::from(dir)) {
+                        Some(x) if self.worldmap.is_active(x) => x,
+                        _ => continue,
+                    };
+                    let i = *neighbour_index.entry(next).or_insert_with(|| {
+                        neighbours.push((next, [false; 6]));
+                        neighbours.len() - 1
+                    });
+                    let side: usize = Direction::from(dir).flip().into();
+                    neighbours[i].1[side] = true;
+                }
+            }
+
+            let worldmap = &self.worldmap;
+            // new stack of a neighbour, None if it didn't change
+            let update = |&(square, sides): &(Position, [bool; 6])| -> Option<Vec<WfcTile>> {
+                let mut stack = worldmap[square].clone();
+                for (dir, _) in sides.iter().enumerate().filter(|(_, &x)| x) {
+                    let changed = worldmap.move_(square, &Direction::from(dir)).unwrap();
+                    let flipped: usize = Direction::from(dir).flip().into();
+                    let connections: HashSet<usize> = worldmap[changed].iter().map(|tile| tile.connection_types[flipped]).collect();
+                    stack.retain(|tile| connections.contains(&tile.connection_types[dir]));
+                }
+                if stack.len() == worldmap[square].len() { None } else { Some(stack) }
+            };
+            let updates: Vec<Option<Vec<WfcTile>>> = if threads <= 1 || neighbours.len() < min_wave {
+                neighbours.iter().map(update).collect()
+            } else {
+                let update = &update;
+                let chunk_size = neighbours.len().div_ceil(threads);
+                std::thread::scope(|scope| {
+                    let handles: Vec<_> = neighbours.chunks(chunk_size)
+                        .map(|chunk| scope.spawn(move || chunk.iter().map(update).collect::<Vec<_>>()))
+                        .collect();
+                    handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+                })
+            };
+
+            wave.clear();
+            for ((square, sides), stack) in neighbours.into_iter().zip(updates) {
+                let stack = match stack {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if self.explain {
+                    for (dir, _) in sides.iter().enumerate().filter(|(_, &x)| x) {
+                        let from = self.worldmap.move_(square, &Direction::from(dir)).unwrap();
+                        let side: usize = Direction::from(dir).flip().into();
+                        let mut sockets: Vec<usize> = self.worldmap[from].iter().map(|tile| tile.connection_types[side]).collect();
+                        sockets.sort();
+                        sockets.dedup();
+                        self.record(square, Cause::Neighbour { from, side, sockets }, stack.len());
+                    }
+                }
+                if stack.is_empty() {
+                    return Err(self.contradiction_error(format!("error: tile-stack reduced to 0!!!  tile: {:?}", square), square));
+                }
+                self.set_stack(square, stack);
+                wave.push(square);
+            }
+        }
+        Ok(())
+    }
+
     // wfcstate: init, running, error, done
     // wfcstep should do propagation
     pub fn run(&mut self) -> Result<&Worldmap, String> {
@@ -579,3 +1268,226 @@ impl WFC {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilesets::{pipes, stairs_3d};
+
+    fn solve(rng_streams: RngStreams, propagation: Propagation) -> String {
+        solve_tiles(pipes().1, [6, 4, 1], rng_streams, propagation)
+    }
+
+    fn solve_tiles(tiles: Vec<WfcTile>, size: Position, rng_streams: RngStreams, propagation: Propagation) -> String {
+        let [x, y, z] = size;
+        let mut wfc = WFC::init(Worldmap::new3d(x, y, z), tiles, 1);
+        wfc.rng_streams = rng_streams;
+        wfc.propagation = propagation;
+        if let Err(e) = wfc.run() {
+            return e;
+        }
+        wfc.worldmap.values.iter()
+            .map(|stack| format!("{}:{}", stack[0].index, stack[0].angle))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    // changing these means every existing seed gives a different worldmap
+    #[test]
+    fn golden_worldmap() {
+        assert_eq!(solve(RngStreams::Single, Propagation::Serial),
+            "9:180 8:180 13:180 12:180 11:180 10:180 3:0 6:180 9:180 8:180 7:0 13:180 \
+             14:180 3:0 3:90 10:0 11:0 9:180 10:180 3:270 3:180 14:0 3:0 7:0");
+        assert_eq!(solve(RngStreams::PerRegion([1, 1, 1]), Propagation::Serial),
+            "0:180 6:0 3:90 13:90 9:90 3:0 4:90 2:0 3:270 3:180 12:90 8:90 \
+             0:0 4:0 5:90 2:0 13:90 9:90 10:0 11:0 5:180 2:0 14:180 6:90");
+    }
+
+    fn is_solved(wfc: &WFC) -> bool {
+        wfc.worldmap.values.iter().all(|stack| stack.len() == 1)
+    }
+
+    #[test]
+    fn masked_squares_stay_empty() {
+        let (_, tiles, _) = pipes();
+        // ring around a masked-out hole, pipes can't lead into it
+        let mut worldmap = Worldmap::with_mask(5, 5, 1, |[x, y, _]| x == 0 || y == 0 || x == 4 || y == 4);
+        worldmap.set_mask_sockets(Some(vec![0]));
+        let mut wfc = WFC::init(worldmap, tiles, 1);
+        wfc.run().unwrap();
+        for &square in &wfc.squares_list {
+            let stack = &wfc.worldmap[square];
+            if !wfc.worldmap.is_active(square) {
+                assert!(stack.is_empty(), "{:?}", square);
+                continue;
+            }
+            assert_eq!(stack.len(), 1, "{:?}", square);
+            for dir in 0..6 {
+                let next = wfc.worldmap.move_(square, &Direction::from(dir));
+                if next.is_some_and(|x| !wfc.worldmap.is_active(x)) {
+                    assert_eq!(stack[0].connection_types[dir], 0, "{:?}", square);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn boundaries_hold_after_solving() {
+        let (_, tiles, _) = pipes();
+        let mut worldmap = Worldmap::new3d(5, 4, 1);
+        worldmap.set_boundary(Direction::EAST, Boundary::Periodic);
+        worldmap.set_boundary(Direction::NORTH, Boundary::Fixed(vec![0]));
+        let mut wfc = WFC::init(worldmap, tiles, 1);
+        wfc.run().unwrap();
+        assert!(is_solved(&wfc));
+        let worldmap = &wfc.worldmap;
+        // east edge wraps around and connects to the west edge
+        for y in 0..4 {
+            assert_eq!(worldmap[(4, y)][0].connection_types[1], worldmap[(0, y)][0].connection_types[3], "row {}", y);
+        }
+        for x in 0..5 {
+            assert_eq!(worldmap[(x, 0)][0].connection_types[0], 0, "column {}", x);
+        }
+    }
+
+    #[test]
+    fn boundaries_are_propagated() {
+        let (_, tiles, _) = pipes();
+        let mut worldmap = Worldmap::new3d(6, 6, 1);
+        worldmap.set_boundaries(Boundary::Fixed(vec![0]));
+        let mut wfc = WFC::init(worldmap, tiles, 1);
+        // nothing is left for arc consistency to remove
+        assert_eq!(wfc.worldmap.values, crate::feasibility::check(&wfc).domains.values);
+
+        wfc.run().unwrap();
+        // nothing around the region to propagate from, only the boundaries
+        let region = Region::Box([0, 0, 0], [5, 5, 0]);
+        let mut uncollapsed = wfc.clone();
+        uncollapsed.uncollapse(&region).unwrap();
+        assert_eq!(uncollapsed.worldmap.values, crate::feasibility::check(&uncollapsed).domains.values);
+    }
+
+    #[test]
+    fn manual_changes_are_checked() {
+        let (_, tiles, _) = pipes();
+        let (empty, line) = (tiles[1], tiles[2]);
+        let mut wfc = WFC::init(Worldmap::with_mask(6, 6, 1, |x| x != [2, 2, 0]), tiles.clone(), 1);
+        let before = wfc.worldmap.values.clone();
+        assert!(wfc.add_tile([6, 0, 0], line).is_err());
+        assert_eq!(wfc.collapse_at([6, 0, 0], line), Err(CollapseError::OutOfBounds([6, 0, 0])));
+        assert_eq!(wfc.collapse_at([2, 2, 0], line), Err(CollapseError::MaskedOut([2, 2, 0])));
+        assert_eq!(wfc.worldmap.values, before);
+
+        wfc.surround_worldmap_2d(&empty).unwrap();
+        assert_eq!(wfc.collapse_at([0, 0, 0], line), Err(CollapseError::NotInDomain([0, 0, 0], line)));
+        assert_eq!(wfc.ban([0, 0, 0], empty), Err(CollapseError::LastTile([0, 0, 0], empty)));
+
+        // one of the two squares has to stay empty, which a horizontal line next to it rules out
+        let mut wfc = WFC::init(Worldmap::new3d(2, 1, 1), tiles.clone(), 1);
+        wfc.add_constraint(Constraint::at_least(TileFilter::Indices(vec![empty.index]), 1)).unwrap();
+        let before = wfc.worldmap.values.clone();
+        assert!(matches!(wfc.collapse_at([0, 0, 0], line), Err(CollapseError::Contradiction(..))));
+        assert_eq!(wfc.worldmap.values, before);
+        assert!(wfc.steps.is_empty());
+
+        // without vertical connections both squares are empty or neither is
+        let mut worldmap = Worldmap::new3d(2, 1, 1);
+        worldmap.set_boundary(Direction::NORTH, Boundary::Fixed(vec![0]));
+        worldmap.set_boundary(Direction::SOUTH, Boundary::Fixed(vec![0]));
+        let mut wfc = WFC::init(worldmap, tiles, 1);
+        wfc.add_constraint(Constraint::at_least(TileFilter::Indices(vec![empty.index]), 1)).unwrap();
+        let before = wfc.worldmap.values.clone();
+        assert!(matches!(wfc.ban([0, 0, 0], empty), Err(CollapseError::Contradiction(..))));
+        assert_eq!(wfc.worldmap.values, before);
+        wfc.run().unwrap();
+        assert_eq!(wfc.worldmap.values, vec![vec![empty]; 2]);
+    }
+
+    #[test]
+    fn redone_manual_step_keeps_cursor() {
+        let (_, tiles, _) = pipes();
+        let mut wfc = WFC::init(Worldmap::new3d(6, 4, 1), tiles, 1);
+        let tile = wfc.worldmap[(5, 3)][0];
+        wfc.collapse_at([5, 3, 0], tile).unwrap();
+        assert!(wfc.undo_step());
+        assert!(wfc.redo_step().unwrap());
+        assert_eq!(wfc.worldmap[(5, 3)], [tile]);
+        wfc.run().unwrap();
+        assert!(is_solved(&wfc));
+    }
+
+    #[test]
+    fn undo_redo_and_branch() {
+        let (_, tiles, _) = pipes();
+        let mut wfc = WFC::init(Worldmap::new3d(6, 4, 1), tiles, 1);
+        assert!(!wfc.undo_step());
+        assert!(!wfc.redo_step().unwrap());
+        assert!(!wfc.branch_step().unwrap());
+
+        for _ in 0..3 {
+            wfc.wfc_step().unwrap();
+        }
+        let before = wfc.worldmap.values.clone();
+        wfc.wfc_step().unwrap();
+        let after = wfc.worldmap.values.clone();
+        let step = wfc.steps.last().unwrap().clone();
+
+        assert!(wfc.undo_step());
+        assert_eq!(wfc.worldmap.values, before);
+        assert!(wfc.redo_step().unwrap());
+        assert_eq!(wfc.worldmap.values, after);
+
+        assert!(wfc.undo_step());
+        assert!(wfc.branch_step().unwrap());
+        assert_eq!(wfc.steps.len(), 3);
+        assert!(wfc.undone.is_empty());
+        let square = wfc.steps.last().unwrap().square;
+        assert!(square != step.square || wfc.worldmap[square] != [step.tile]);
+        wfc.run().unwrap();
+        assert!(is_solved(&wfc));
+    }
+
+    #[test]
+    fn failed_branch_changes_nothing() {
+        let (_, tiles, _) = pipes();
+        let mut wfc = WFC::init(Worldmap::new3d(6, 4, 1), tiles.clone(), 1);
+        wfc.surround_worldmap_2d(&tiles[1]).unwrap();
+        // corners have no other tile to branch to
+        wfc.collapse_at([0, 0, 0], tiles[1]).unwrap();
+        wfc.collapse_at([0, 3, 0], tiles[1]).unwrap();
+        wfc.undo_step();
+
+        let text = wfc.save();
+        assert!(wfc.branch_step().is_err());
+        assert_eq!(wfc.save(), text);
+        assert_eq!(wfc.undone.len(), 1);
+    }
+
+    #[test]
+    fn regenerates_only_the_region() {
+        let (_, tiles, _) = pipes();
+        let mut wfc = WFC::init(Worldmap::new3d(8, 8, 1), tiles, 1);
+        wfc.run().unwrap();
+        let before = wfc.worldmap.clone();
+
+        let region = wfc.regenerate(&Region::Box([3, 3, 0], [4, 4, 0]), 7, 2).unwrap();
+        assert_eq!(wfc.seed, 1);
+        assert!(is_solved(&wfc));
+        for &square in &wfc.squares_list {
+            if !region.contains(&wfc.worldmap, square) {
+                assert_eq!(wfc.worldmap[square], before[square], "{:?}", square);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_propagation_matches_serial() {
+        // waves of shipped tilesets stay small, so a small `min_wave` gets the threads going
+        let parallel = Propagation::Parallel { threads: 4, min_wave: 8 };
+        assert_eq!(solve_tiles(pipes().1, [40, 40, 1], RngStreams::Single, parallel),
+            solve_tiles(pipes().1, [40, 40, 1], RngStreams::Single, Propagation::Serial));
+        let (_, tiles, _) = stairs_3d();
+        assert_eq!(solve_tiles(tiles.clone(), [20, 20, 20], RngStreams::Single, parallel),
+            solve_tiles(tiles, [20, 20, 20], RngStreams::Single, Propagation::Serial));
+    }
+}